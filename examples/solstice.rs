@@ -1,32 +1,23 @@
 use glutin::event::DeviceEvent;
 
-fn draw<'a>(
-    source: &'a str,
+/// Expands the script's defines before parsing it.
+fn parse(source: &str) -> Result<eisenscript::RuleSet, eisenscript::Error> {
+    eisenscript::Preprocessor::new(source)
+        .process()
+        .parser()
+        .rules()
+}
+
+fn draw(
+    source: &str,
     assets: &Assets,
     camera: &solstice_2d::Transform3D,
-) -> Result<solstice_2d::DrawList<'static>, eisenscript::Error<'a>> {
-    let parser = eisenscript::Parser::new(eisenscript::Lexer::new(source));
-    let rules = parser.rules()?;
-
-    fn hsv_to_rgb(h: f32, s: f32, v: f32) -> [f32; 3] {
-        let i = (h * 6.).floor();
-        let f = (h * 6.) - i;
-        let p = v * (1. - s);
-        let q = v * (1. - f * s);
-        let t = v * (1. - (1. - f) * s);
-        match i as usize % 6 {
-            0 => [v, t, p],
-            1 => [q, v, p],
-            2 => [p, v, t],
-            3 => [p, q, v],
-            4 => [t, p, v],
-            5 => [v, p, q],
-            _ => panic!(),
-        }
-    }
+) -> Result<solstice_2d::DrawList<'static>, eisenscript::Error> {
+    let rules = parse(source)?;
 
     fn tx_to_color(tx: &eisenscript::Transform) -> solstice_2d::Color {
-        let [r, g, b] = hsv_to_rgb((tx.hue % 360.) / 360., tx.sat, tx.brightness);
+        let eisenscript::Color { r, g, b } =
+            eisenscript::Color::from_hsv(tx.hue, tx.sat, tx.brightness);
         solstice_2d::Color::new(r, g, b, tx.alpha)
     }
 
@@ -62,6 +53,27 @@ fn draw<'a>(
     Ok(dl)
 }
 
+/// The view saved with the script, or a default looking at the origin.
+fn initial_camera(source: &str) -> solstice_2d::Transform3D {
+    let camera = parse(source).ok().and_then(|rules| rules.camera().copied());
+    match camera {
+        Some(camera) => {
+            use solstice_2d::{Rad as R, Transform3D};
+            let rotation = nalgebra::Matrix3::from_row_slice(&camera.rotation);
+            let (roll, pitch, yaw) =
+                nalgebra::Rotation3::from_matrix_unchecked(rotation).euler_angles();
+            let [tx, ty, tz] = camera.translation;
+            let [px, py, pz] = camera.pivot;
+            let s = camera.scale;
+            Transform3D::translation(tx, ty, tz)
+                * Transform3D::scale(s, s, s)
+                * Transform3D::rotation(R(roll), R(pitch), R(yaw))
+                * Transform3D::translation(-px, -py, -pz)
+        }
+        None => solstice_2d::Transform3D::translation(0., -2., -5.),
+    }
+}
+
 struct Assets {
     shader: solstice_2d::Shader,
     plane: solstice_2d::Shader,
@@ -98,10 +110,10 @@ fn main() {
     };
 
     let assets = Assets { shader, plane };
-    let mut camera = solstice_2d::Transform3D::translation(0., -2., -5.);
 
     let path = root_path.join("examples").join("src.eis");
     let mut source = std::fs::read_to_string(&path).unwrap();
+    let mut camera = initial_camera(&source);
     let mut dl = draw(&source, &assets, &camera).unwrap_or_else(|err| {
         eprintln!("{}", err);
         solstice_2d::DrawList::default()
@@ -138,7 +150,7 @@ fn main() {
 
     enum MouseButtonState {
         Up,
-        Down { start: [f32; 2] },
+        Down,
     }
     struct MouseState {
         position: [f32; 2],
@@ -158,18 +170,14 @@ fn main() {
         match event {
             Event::WindowEvent { event, .. } => match event {
                 WindowEvent::CloseRequested => *cf = ControlFlow::Exit,
-                WindowEvent::MouseInput { state, button, .. } => {
-                    if let MouseButton::Left = button {
-                        match state {
-                            ElementState::Pressed => {
-                                mouse.button = MouseButtonState::Down {
-                                    start: mouse.position,
-                                }
-                            }
-                            ElementState::Released => mouse.button = MouseButtonState::Up,
-                        }
-                    }
-                }
+                WindowEvent::MouseInput {
+                    state,
+                    button: MouseButton::Left,
+                    ..
+                } => match state {
+                    ElementState::Pressed => mouse.button = MouseButtonState::Down,
+                    ElementState::Released => mouse.button = MouseButtonState::Up,
+                },
                 WindowEvent::KeyboardInput {
                     input:
                         KeyboardInput {
@@ -193,36 +201,23 @@ fn main() {
                 }
                 _ => {}
             },
-            Event::DeviceEvent { event, .. } => match event {
-                DeviceEvent::MouseMotion { delta: (dx, dy) } => {
-                    let glutin::dpi::PhysicalSize { width, height } =
-                        window_ctx.window().inner_size().cast::<f32>();
-                    let arcball = |x: f32, y: f32| -> [f32; 3] {
-                        let [px, py] = [x / width * 2. - 1., y / height * 2. - 1.];
-                        let py = -py;
-                        let squared = px * px + py * py;
-                        if squared <= 1. {
-                            [px, py, (1. - squared).sqrt()]
-                        } else {
-                            nalgebra::Vector3::new(px, py, 0.).normalize().into()
-                        }
-                    };
+            Event::DeviceEvent {
+                event: DeviceEvent::MouseMotion { delta: (dx, dy) },
+                ..
+            } => {
+                let [mx, my] = &mut mouse.position;
+                *mx += dx as f32;
+                *my += dy as f32;
 
-                    let [mx, my] = &mut mouse.position;
-                    *mx += dx as f32;
-                    *my += dy as f32;
-
-                    if let MouseButtonState::Down { .. } = &mouse.button {
-                        use solstice_2d::Rad as R;
-                        camera *= solstice_2d::Transform3D::rotation(
-                            R(dx as f32 / 100.),
-                            R(dy as f32 / 100.),
-                            R(0.),
-                        );
-                    }
+                if let MouseButtonState::Down = &mouse.button {
+                    use solstice_2d::Rad as R;
+                    camera *= solstice_2d::Transform3D::rotation(
+                        R(dx as f32 / 100.),
+                        R(dy as f32 / 100.),
+                        R(0.),
+                    );
                 }
-                _ => {}
-            },
+            }
             Event::MainEventsCleared => window_ctx.window().request_redraw(),
             Event::RedrawRequested(_) => {
                 if let Ok(src) = tx.try_recv() {
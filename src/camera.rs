@@ -0,0 +1,36 @@
+/// The view saved with a script by `set translation`, `set rotation`, `set pivot` and
+/// `set scale`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Camera {
+    pub translation: [f32; 3],
+    /// A rotation matrix in row-major order.
+    pub rotation: [f32; 9],
+    /// The point the view rotates around.
+    pub pivot: [f32; 3],
+    pub scale: f32,
+}
+
+impl Camera {
+    /// The transform from world to view space: the scene is moved so the pivot is at the
+    /// origin, rotated, scaled and finally translated.
+    pub fn view(&self) -> mint::ColumnMatrix4<f32> {
+        let [px, py, pz] = self.pivot;
+        let rotation = nalgebra::Matrix3::from_row_slice(&self.rotation).to_homogeneous();
+        let view = nalgebra::Matrix4::new_translation(&self.translation.into())
+            * nalgebra::Matrix4::new_scaling(self.scale)
+            * rotation
+            * nalgebra::Matrix4::new_translation(&nalgebra::Vector3::new(-px, -py, -pz));
+        view.into()
+    }
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self {
+            translation: [0., 0., -20.],
+            rotation: [1., 0., 0., 0., 1., 0., 0., 0., 1.],
+            pivot: [0., 0., 0.],
+            scale: 1.,
+        }
+    }
+}
@@ -0,0 +1,434 @@
+/// An RGB color with components in `0..=1`.
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+pub struct Color {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+}
+
+impl Color {
+    pub const fn new(r: f32, g: f32, b: f32) -> Self {
+        Self { r, g, b }
+    }
+
+    const fn from_bytes(r: u8, g: u8, b: u8) -> Self {
+        Self::new(r as f32 / 255., g as f32 / 255., b as f32 / 255.)
+    }
+
+    /// Parses `#rgb` or `#rrggbb`.
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        let digits = hex.strip_prefix('#')?;
+        if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let channel = |i: usize, width: usize| {
+            let value = u8::from_str_radix(&digits[i * width..(i + 1) * width], 16).ok()?;
+            Some(if width == 1 { value * 17 } else { value })
+        };
+        let width = match digits.len() {
+            3 => 1,
+            6 => 2,
+            _ => return None,
+        };
+        Some(Self::from_bytes(
+            channel(0, width)?,
+            channel(1, width)?,
+            channel(2, width)?,
+        ))
+    }
+
+    /// Looks up an SVG/X11 color keyword, ignoring case.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+        NAMED_COLORS
+            .binary_search_by(|(candidate, _)| (*candidate).cmp(name.as_str()))
+            .ok()
+            .map(|index| NAMED_COLORS[index].1)
+    }
+
+    /// Parses either a hex code or a color name.
+    pub fn parse(color: &str) -> Option<Self> {
+        if color.starts_with('#') {
+            Self::from_hex(color)
+        } else {
+            Self::from_name(color)
+        }
+    }
+
+    /// Builds a color from a hue in degrees and saturation and value in `0..=1`.
+    pub fn from_hsv(hue: f32, sat: f32, value: f32) -> Self {
+        let hue = hue.rem_euclid(360.) / 60.;
+        let i = hue.floor();
+        let f = hue - i;
+        let p = value * (1. - sat);
+        let q = value * (1. - f * sat);
+        let t = value * (1. - (1. - f) * sat);
+        match i as usize % 6 {
+            0 => Self::new(value, t, p),
+            1 => Self::new(q, value, p),
+            2 => Self::new(p, value, t),
+            3 => Self::new(p, q, value),
+            4 => Self::new(t, p, value),
+            _ => Self::new(value, p, q),
+        }
+    }
+
+    /// The hue in degrees followed by saturation and value in `0..=1`.
+    pub fn hsv(&self) -> [f32; 3] {
+        let Self { r, g, b } = *self;
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let delta = max - min;
+
+        let hue = if delta == 0. {
+            0.
+        } else if max == r {
+            60. * ((g - b) / delta).rem_euclid(6.)
+        } else if max == g {
+            60. * ((b - r) / delta + 2.)
+        } else {
+            60. * ((r - g) / delta + 4.)
+        };
+        let sat = if max == 0. { 0. } else { delta / max };
+        [hue, sat, max]
+    }
+}
+
+/// The colors that `color random` draws from, chosen with `set colorpool`.
+#[derive(Debug, Default, Clone, PartialEq, PartialOrd)]
+pub enum ColorPool {
+    /// Fully saturated, full brightness colors of any hue.
+    #[default]
+    RandomHue,
+    /// Uniformly distributed RGB colors.
+    RandomRgb,
+    /// Shades of grey from black to white.
+    Greyscale,
+    /// `list:red,#0f0,blue`: the listed colors with equal probability.
+    List(Vec<Color>),
+    /// `image:file.png`: the pixels of an image once loaded with
+    /// [`ColorPool::load_image`]. Until then colors are drawn as for `RandomHue`.
+    Image { path: String, pixels: Vec<Color> },
+}
+
+impl ColorPool {
+    /// Parses a `set colorpool` argument such as `randomrgb` or `list:orange,white`.
+    pub fn parse(spec: &str) -> Option<Self> {
+        if let Some(colors) = spec.strip_prefix("list:") {
+            let colors = colors
+                .split(',')
+                .map(Color::parse)
+                .collect::<Option<Vec<_>>>()?;
+            return Some(Self::List(colors));
+        }
+        if let Some(path) = spec.strip_prefix("image:") {
+            return Some(Self::Image {
+                path: path.to_string(),
+                pixels: vec![],
+            });
+        }
+        match spec.to_ascii_lowercase().as_str() {
+            "randomhue" => Some(Self::RandomHue),
+            "randomrgb" => Some(Self::RandomRgb),
+            "greyscale" | "grayscale" => Some(Self::Greyscale),
+            _ => None,
+        }
+    }
+
+    /// Reads the pixels of an `image:` pool, resolving its path against `dir`.
+    ///
+    /// Scripts can't name files outside `dir`: absolute paths and `..` are rejected. Other
+    /// pools are left as they are.
+    #[cfg(feature = "image")]
+    pub fn load_image(&mut self, dir: &std::path::Path) -> image::ImageResult<()> {
+        if let Self::Image { path, pixels } = self {
+            let relative = std::path::Path::new(path);
+            let contained = relative
+                .components()
+                .all(|component| matches!(component, std::path::Component::Normal(_)));
+            if !contained {
+                return Err(image::ImageError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "color pool images must be relative to the script",
+                )));
+            }
+            *pixels = image::open(dir.join(relative))?
+                .into_rgb8()
+                .pixels()
+                .map(|image::Rgb([r, g, b])| Color::from_bytes(*r, *g, *b))
+                .collect();
+        }
+        Ok(())
+    }
+
+    pub fn sample<R: rand::Rng + ?Sized>(&self, rng: &mut R) -> Color {
+        match self {
+            Self::RandomHue => Color::from_hsv(rng.gen_range(0. ..360.), 1., 1.),
+            Self::Image { pixels, .. } if pixels.is_empty() => Self::RandomHue.sample(rng),
+            Self::RandomRgb => Color::new(rng.gen(), rng.gen(), rng.gen()),
+            Self::Greyscale => {
+                let value = rng.gen();
+                Color::new(value, value, value)
+            }
+            Self::List(colors) | Self::Image { pixels: colors, .. } => {
+                colors[rng.gen_range(0..colors.len())]
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for Color {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s).ok_or(())
+    }
+}
+
+const fn named(name: &'static str, r: u8, g: u8, b: u8) -> (&'static str, Color) {
+    (name, Color::from_bytes(r, g, b))
+}
+
+/// The SVG color keywords, sorted by name.
+const NAMED_COLORS: [(&str, Color); 147] = [
+    named("aliceblue", 240, 248, 255),
+    named("antiquewhite", 250, 235, 215),
+    named("aqua", 0, 255, 255),
+    named("aquamarine", 127, 255, 212),
+    named("azure", 240, 255, 255),
+    named("beige", 245, 245, 220),
+    named("bisque", 255, 228, 196),
+    named("black", 0, 0, 0),
+    named("blanchedalmond", 255, 235, 205),
+    named("blue", 0, 0, 255),
+    named("blueviolet", 138, 43, 226),
+    named("brown", 165, 42, 42),
+    named("burlywood", 222, 184, 135),
+    named("cadetblue", 95, 158, 160),
+    named("chartreuse", 127, 255, 0),
+    named("chocolate", 210, 105, 30),
+    named("coral", 255, 127, 80),
+    named("cornflowerblue", 100, 149, 237),
+    named("cornsilk", 255, 248, 220),
+    named("crimson", 220, 20, 60),
+    named("cyan", 0, 255, 255),
+    named("darkblue", 0, 0, 139),
+    named("darkcyan", 0, 139, 139),
+    named("darkgoldenrod", 184, 134, 11),
+    named("darkgray", 169, 169, 169),
+    named("darkgreen", 0, 100, 0),
+    named("darkgrey", 169, 169, 169),
+    named("darkkhaki", 189, 183, 107),
+    named("darkmagenta", 139, 0, 139),
+    named("darkolivegreen", 85, 107, 47),
+    named("darkorange", 255, 140, 0),
+    named("darkorchid", 153, 50, 204),
+    named("darkred", 139, 0, 0),
+    named("darksalmon", 233, 150, 122),
+    named("darkseagreen", 143, 188, 143),
+    named("darkslateblue", 72, 61, 139),
+    named("darkslategray", 47, 79, 79),
+    named("darkslategrey", 47, 79, 79),
+    named("darkturquoise", 0, 206, 209),
+    named("darkviolet", 148, 0, 211),
+    named("deeppink", 255, 20, 147),
+    named("deepskyblue", 0, 191, 255),
+    named("dimgray", 105, 105, 105),
+    named("dimgrey", 105, 105, 105),
+    named("dodgerblue", 30, 144, 255),
+    named("firebrick", 178, 34, 34),
+    named("floralwhite", 255, 250, 240),
+    named("forestgreen", 34, 139, 34),
+    named("fuchsia", 255, 0, 255),
+    named("gainsboro", 220, 220, 220),
+    named("ghostwhite", 248, 248, 255),
+    named("gold", 255, 215, 0),
+    named("goldenrod", 218, 165, 32),
+    named("gray", 128, 128, 128),
+    named("green", 0, 128, 0),
+    named("greenyellow", 173, 255, 47),
+    named("grey", 128, 128, 128),
+    named("honeydew", 240, 255, 240),
+    named("hotpink", 255, 105, 180),
+    named("indianred", 205, 92, 92),
+    named("indigo", 75, 0, 130),
+    named("ivory", 255, 255, 240),
+    named("khaki", 240, 230, 140),
+    named("lavender", 230, 230, 250),
+    named("lavenderblush", 255, 240, 245),
+    named("lawngreen", 124, 252, 0),
+    named("lemonchiffon", 255, 250, 205),
+    named("lightblue", 173, 216, 230),
+    named("lightcoral", 240, 128, 128),
+    named("lightcyan", 224, 255, 255),
+    named("lightgoldenrodyellow", 250, 250, 210),
+    named("lightgray", 211, 211, 211),
+    named("lightgreen", 144, 238, 144),
+    named("lightgrey", 211, 211, 211),
+    named("lightpink", 255, 182, 193),
+    named("lightsalmon", 255, 160, 122),
+    named("lightseagreen", 32, 178, 170),
+    named("lightskyblue", 135, 206, 250),
+    named("lightslategray", 119, 136, 153),
+    named("lightslategrey", 119, 136, 153),
+    named("lightsteelblue", 176, 196, 222),
+    named("lightyellow", 255, 255, 224),
+    named("lime", 0, 255, 0),
+    named("limegreen", 50, 205, 50),
+    named("linen", 250, 240, 230),
+    named("magenta", 255, 0, 255),
+    named("maroon", 128, 0, 0),
+    named("mediumaquamarine", 102, 205, 170),
+    named("mediumblue", 0, 0, 205),
+    named("mediumorchid", 186, 85, 211),
+    named("mediumpurple", 147, 112, 219),
+    named("mediumseagreen", 60, 179, 113),
+    named("mediumslateblue", 123, 104, 238),
+    named("mediumspringgreen", 0, 250, 154),
+    named("mediumturquoise", 72, 209, 204),
+    named("mediumvioletred", 199, 21, 133),
+    named("midnightblue", 25, 25, 112),
+    named("mintcream", 245, 255, 250),
+    named("mistyrose", 255, 228, 225),
+    named("moccasin", 255, 228, 181),
+    named("navajowhite", 255, 222, 173),
+    named("navy", 0, 0, 128),
+    named("oldlace", 253, 245, 230),
+    named("olive", 128, 128, 0),
+    named("olivedrab", 107, 142, 35),
+    named("orange", 255, 165, 0),
+    named("orangered", 255, 69, 0),
+    named("orchid", 218, 112, 214),
+    named("palegoldenrod", 238, 232, 170),
+    named("palegreen", 152, 251, 152),
+    named("paleturquoise", 175, 238, 238),
+    named("palevioletred", 219, 112, 147),
+    named("papayawhip", 255, 239, 213),
+    named("peachpuff", 255, 218, 185),
+    named("peru", 205, 133, 63),
+    named("pink", 255, 192, 203),
+    named("plum", 221, 160, 221),
+    named("powderblue", 176, 224, 230),
+    named("purple", 128, 0, 128),
+    named("red", 255, 0, 0),
+    named("rosybrown", 188, 143, 143),
+    named("royalblue", 65, 105, 225),
+    named("saddlebrown", 139, 69, 19),
+    named("salmon", 250, 128, 114),
+    named("sandybrown", 244, 164, 96),
+    named("seagreen", 46, 139, 87),
+    named("seashell", 255, 245, 238),
+    named("sienna", 160, 82, 45),
+    named("silver", 192, 192, 192),
+    named("skyblue", 135, 206, 235),
+    named("slateblue", 106, 90, 205),
+    named("slategray", 112, 128, 144),
+    named("slategrey", 112, 128, 144),
+    named("snow", 255, 250, 250),
+    named("springgreen", 0, 255, 127),
+    named("steelblue", 70, 130, 180),
+    named("tan", 210, 180, 140),
+    named("teal", 0, 128, 128),
+    named("thistle", 216, 191, 216),
+    named("tomato", 255, 99, 71),
+    named("turquoise", 64, 224, 208),
+    named("violet", 238, 130, 238),
+    named("wheat", 245, 222, 179),
+    named("white", 255, 255, 255),
+    named("whitesmoke", 245, 245, 245),
+    named("yellow", 255, 255, 0),
+    named("yellowgreen", 154, 205, 50),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_sorted() {
+        assert!(NAMED_COLORS.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Color::parse("white"), Some(Color::new(1., 1., 1.)));
+        assert_eq!(Color::parse("Red"), Some(Color::new(1., 0., 0.)));
+        assert_eq!(Color::parse("#0a0"), Color::parse("#00aa00"));
+        assert_eq!(Color::parse("#fff"), Color::parse("white"));
+        assert_eq!(Color::parse("#F00"), Color::parse("red"));
+        assert_eq!(Color::parse("#0404A2"), Some(Color::from_bytes(4, 4, 162)));
+        assert_eq!(Color::parse("#12345"), None);
+        assert_eq!(Color::parse("notacolor"), None);
+    }
+
+    #[test]
+    fn hsv_round_trip() {
+        for name in ["red", "orange", "teal", "navy", "grey", "white", "black"] {
+            let color = Color::from_name(name).unwrap();
+            let [h, s, v] = color.hsv();
+            let result = Color::from_hsv(h, s, v);
+            approx::assert_abs_diff_eq!(result.r, color.r, epsilon = 0.0001);
+            approx::assert_abs_diff_eq!(result.g, color.g, epsilon = 0.0001);
+            approx::assert_abs_diff_eq!(result.b, color.b, epsilon = 0.0001);
+        }
+        assert_eq!(Color::from_name("blue").unwrap().hsv(), [240., 1., 1.]);
+    }
+
+    #[test]
+    fn color_pools() {
+        assert_eq!(ColorPool::parse("RandomRGB"), Some(ColorPool::RandomRgb));
+        assert_eq!(ColorPool::parse("greyscale"), Some(ColorPool::Greyscale));
+        assert_eq!(
+            ColorPool::parse("list:#fff,black"),
+            Some(ColorPool::List(vec![
+                Color::new(1., 1., 1.),
+                Color::new(0., 0., 0.)
+            ]))
+        );
+        assert_eq!(ColorPool::parse("list:white,notacolor"), None);
+        let unloaded = ColorPool::parse("image:does/not/exist.png").unwrap();
+        assert_eq!(
+            unloaded,
+            ColorPool::Image {
+                path: "does/not/exist.png".to_string(),
+                pixels: vec![]
+            }
+        );
+        assert_eq!(ColorPool::parse("sepia"), None);
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let Color { r, g, b } = ColorPool::Greyscale.sample(&mut rng);
+            assert!(r == g && g == b);
+            let [_, sat, value] = ColorPool::RandomHue.sample(&mut rng).hsv();
+            approx::assert_abs_diff_eq!(sat, 1., epsilon = 0.0001);
+            approx::assert_abs_diff_eq!(value, 1., epsilon = 0.0001);
+            let [_, sat, _] = unloaded.sample(&mut rng).hsv();
+            approx::assert_abs_diff_eq!(sat, 1., epsilon = 0.0001);
+        }
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn image_color_pool() {
+        let dir = std::env::temp_dir().join("eisenscript_color_pool");
+        std::fs::create_dir_all(&dir).unwrap();
+        image::RgbImage::from_fn(2, 1, |x, _y| image::Rgb([255 * x as u8, 0, 0]))
+            .save(dir.join("pool.png"))
+            .unwrap();
+        let mut pool = ColorPool::parse("image:pool.png").unwrap();
+        pool.load_image(&dir).unwrap();
+        let mut outside = ColorPool::parse("image:../eisenscript_color_pool/pool.png").unwrap();
+        let outside = outside.load_image(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            pool,
+            ColorPool::Image {
+                path: "pool.png".to_string(),
+                pixels: vec![Color::new(0., 0., 0.), Color::new(1., 0., 0.)]
+            }
+        );
+        assert!(outside.is_err());
+    }
+}
@@ -13,6 +13,10 @@ pub enum Token {
     RuleDefinition,
     #[regex("[a-zA-Z]+[a-zA-Z0-9]*")]
     RuleInvocation,
+    #[regex(r"triangle\[[^\]]*\]")]
+    Triangle,
+    #[regex("template::[a-zA-Z0-9_-]+")]
+    TemplateMarker,
 
     #[token("set")]
     Set,
@@ -31,11 +35,24 @@ pub enum Token {
     LiteralInteger,
     #[regex("[+-]?[0-9]*[.]?[0-9]+")]
     LiteralFloat,
+    #[regex("#[0-9a-fA-F]+")]
+    LiteralHexColor,
 
     #[token("maxdepth")]
     #[token("md")]
     MaxDepth,
 
+    #[token("maxobjects")]
+    MaxObjects,
+    #[token("recursion")]
+    Recursion,
+    #[token("minsize")]
+    MinSize,
+    #[token("maxsize")]
+    MaxSize,
+    #[token("colorpool")]
+    ColorPool,
+
     #[token("weight")]
     #[token("w")]
     Weight,
@@ -60,6 +77,7 @@ pub enum Token {
     Reflect,
     #[token("blend")]
     Blend,
+    /// Also spelled `m`, which is lexed as a rule name so that rules can still be called `m`.
     #[token("matrix")]
     Matrix,
     #[token("sat")]
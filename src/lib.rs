@@ -1,10 +1,18 @@
+mod camera;
+mod color;
 mod lexer;
 mod parser;
+mod preprocessor;
+mod settings;
 mod transform;
 
 type RulesMap = std::collections::BTreeMap<String, Rule>;
 pub type Lexer<'source> = logos::Lexer<'source, lexer::Token>;
+pub use camera::Camera;
+pub use color::{Color, ColorPool};
 pub use parser::{Error, ErrorKind, Parser};
+pub use preprocessor::{Parameter, ParameterKind, Preprocessed, Preprocessor, SourceMap};
+pub use settings::{Recursion, Setting, Settings};
 pub use transform::Transform;
 
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
@@ -16,12 +24,22 @@ pub enum Primitive {
     Cylinder,
     Line,
     Mesh,
+    /// A named marker such as `template::union-begin` for templates to act on, for example by
+    /// opening and closing CSG blocks. The name is given by [`Geometry::Template`], while a
+    /// bare `template` invocation yields this primitive with no geometry.
+    ///
+    /// Markers only come out in script order under depth first recursion, so CSG scripts need
+    /// `set recursion depth` since breadth first is the default. See
+    /// [`Settings::max_objects`] for how markers are counted.
     Template,
     Other,
+    /// A polygon written inline as `triangle[x,y,z;x,y,z;x,y,z]`, whose corners are given by
+    /// [`Geometry::Triangle`].
+    Triangle,
 }
 
 impl Primitive {
-    pub fn name(&self) -> &str {
+    pub fn name(&self) -> &'static str {
         match self {
             Primitive::Box => "box",
             Primitive::Sphere => "sphere",
@@ -32,10 +50,20 @@ impl Primitive {
             Primitive::Mesh => "mesh",
             Primitive::Template => "template",
             Primitive::Other => "other",
+            Primitive::Triangle => "triangle",
         }
     }
 }
 
+/// The data that goes with primitives written inline in the script.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Geometry {
+    /// The corners of a triangle, in the unit cube's coordinates.
+    Triangle([[f32; 3]; 3]),
+    /// The name of a template marker, such as `union-begin`.
+    Template(String),
+}
+
 #[derive(Debug, Clone, PartialEq)]
 struct RuleDefinition {
     name: String,
@@ -51,83 +79,355 @@ struct Custom {
 }
 
 impl Custom {
-    pub fn iter<'a, 'b: 'a, R>(
+    fn expand<'a>(
         &'a self,
-        ctx: Context<'a>,
-        ctx_mut: &'a mut ContextMut<'b, R>,
-    ) -> Box<dyn Iterator<Item = (Transform, Primitive)> + 'a>
-    where
-        R: rand::Rng,
-    {
-        fn filter(action: &Action) -> Option<&TransformAction> {
+        ctx: &Context<'a>,
+        pending: &mut Vec<Iterations<'a>>,
+        random: &mut Random<'a>,
+    ) {
+        for action in &self.actions {
             match action {
-                Action::Set(_) => None,
-                Action::Transform(tx) => Some(tx),
+                Action::Set(action) => random.set(action),
+                Action::Transform(action) => action.execute(ctx, pending, random),
             }
         }
+    }
 
-        let iter = self
-            .actions
-            .iter()
-            .filter_map(filter)
-            .flat_map(move |action| action.execute(&ctx, ctx_mut));
-        Box::new(iter)
+    /// The names of the rules this one can go on to invoke.
+    fn invoked(&self) -> impl Iterator<Item = &str> {
+        let actions = self.actions.iter().filter_map(|action| match action {
+            Action::Set(_) => None,
+            Action::Transform(action) => Some(action.rule.as_str()),
+        });
+        actions.chain(self.rule.retirement_rule.as_deref())
     }
 }
 
 #[derive(Debug, Clone)]
 struct Ambiguous {
-    name: String,
     actions: Vec<Custom>,
     weights: rand_distr::WeightedIndex<f32>,
 }
 
+impl Ambiguous {
+    fn new(actions: Vec<Custom>) -> Result<Self, ErrorKind> {
+        let weights = Self::weights(&actions)?;
+        Ok(Self { actions, weights })
+    }
+
+    fn push(&mut self, action: Custom) -> Result<(), ErrorKind> {
+        self.actions.push(action);
+        match Self::weights(&self.actions) {
+            Ok(weights) => self.weights = weights,
+            Err(kind) => {
+                self.actions.pop();
+                return Err(kind);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails unless the weights are non-negative, at least one of them is positive and their
+    /// sum is finite.
+    fn weights(actions: &[Custom]) -> Result<rand_distr::WeightedIndex<f32>, ErrorKind> {
+        let weights = actions.iter().map(|action| action.rule.weight);
+        if !weights.clone().sum::<f32>().is_finite() {
+            return Err(ErrorKind::InvalidWeight);
+        }
+        rand_distr::WeightedIndex::new(weights).map_err(|_| ErrorKind::InvalidWeight)
+    }
+}
+
 #[derive(Debug, Clone)]
 enum Rule {
-    Primitive(Primitive),
+    Primitive(Primitive, Option<Geometry>),
     Custom(Custom),
     Ambiguous(Ambiguous),
 }
 
-impl Rule {
-    pub fn name(&self) -> &str {
-        match self {
-            Rule::Primitive(inner) => inner.name(),
-            Rule::Custom(inner) => &inner.rule.name,
-            Rule::Ambiguous(inner) => &inner.name,
+/// A rule waiting to be applied along with the state it was invoked with.
+struct Invocation<'a> {
+    rule: &'a Rule,
+    ctx: Context<'a>,
+}
+
+/// The invocations of one transform action that haven't been applied yet.
+///
+/// Iterations are produced one at a time like an odometer, innermost loop varying fastest, so
+/// that `Settings::max_objects` stops even a huge loop without expanding it first.
+struct Iterations<'a> {
+    loops: &'a [TransformationLoop],
+    name: &'a str,
+    rule: &'a Rule,
+    ctx: Context<'a>,
+    /// The current iteration of each loop, counting from one.
+    indices: Vec<usize>,
+    /// The transform reached by each loop at its current iteration.
+    states: Vec<Transform>,
+    remaining: bool,
+    /// Draws random colors and seeds for these iterations, independently of the order in which
+    /// pending work is applied.
+    rng: rand::rngs::SmallRng,
+    color_pool: &'a ColorPool,
+}
+
+impl<'a> Iterations<'a> {
+    fn new(
+        loops: &'a [TransformationLoop],
+        name: &'a str,
+        rule: &'a Rule,
+        ctx: Context<'a>,
+        seed: u64,
+        color_pool: &'a ColorPool,
+    ) -> Self {
+        Self {
+            loops,
+            name,
+            rule,
+            ctx,
+            indices: vec![],
+            states: vec![],
+            remaining: loops.iter().all(|l| l.count > 0),
+            rng: rand::SeedableRng::seed_from_u64(seed),
+            color_pool,
         }
     }
 
-    pub fn max_depth(&self) -> Option<usize> {
-        match self {
-            Rule::Primitive(_) => None,
-            Rule::Custom(inner) => inner.rule.max_depth,
-            Rule::Ambiguous(_) => None,
+    /// Applies the transform of the loop at `level` once more.
+    fn step(&mut self, level: usize, state: Transform) -> Transform {
+        let (rng, color_pool) = (&mut self.rng, self.color_pool);
+        self.loops[level]
+            .transforms
+            .iter()
+            .fold(state, |state, tx| {
+                state * tx.resolve_random_color(|| color_pool.sample(rng))
+            })
+    }
+}
+
+impl<'a> Iterator for Iterations<'a> {
+    type Item = Invocation<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.remaining {
+            return None;
+        }
+        let start = match self.indices.is_empty() {
+            true => 0,
+            false => {
+                let level = self
+                    .indices
+                    .iter()
+                    .zip(self.loops)
+                    .rposition(|(index, l)| *index < l.count)?;
+                self.indices[level] += 1;
+                self.states[level] = self.step(level, self.states[level]);
+                self.indices.truncate(level + 1);
+                self.states.truncate(level + 1);
+                level + 1
+            }
+        };
+        for level in start..self.loops.len() {
+            let state = self.states.last().copied().unwrap_or(self.ctx.tx);
+            let state = self.step(level, state);
+            self.states.push(state);
+            self.indices.push(1);
         }
+        self.remaining = self
+            .indices
+            .iter()
+            .zip(self.loops)
+            .any(|(index, l)| *index < l.count);
+        let tx = self.states.last().copied().unwrap_or(self.ctx.tx);
+        let seed = rand::Rng::gen(&mut self.rng);
+        Some(Invocation {
+            rule: self.rule,
+            ctx: self.ctx.descend(tx, seed),
+        })
     }
+}
 
-    fn iter<'a, 'b: 'a, R>(
-        &'a self,
-        ctx: Context<'a>,
-        ctx_mut: &'a mut ContextMut<'b, R>,
-    ) -> Vec<(Transform, Primitive)>
-    where
-        R: rand::Rng,
-    {
-        match self {
-            Rule::Primitive(inner) => vec![(ctx.tx, *inner)],
-            Rule::Custom(inner) => inner.iter(ctx, ctx_mut).collect(),
-            Rule::Ambiguous(inner) => {
-                let index = rand::Rng::sample(ctx_mut.rng, &inner.weights);
-                inner.actions[index].iter(ctx, ctx_mut).collect()
+/// Applies rules from an explicit queue of pending invocations.
+///
+/// Depth first evaluation treats the queue as a stack, while breadth first evaluation applies
+/// every rule of one generation before moving on to the next so that `Settings::max_objects`
+/// cuts the structure off evenly.
+///
+/// Keeping the pending work on the heap rather than the call stack means deep recursion is
+/// bounded by `Settings::max_depth` instead of overflowing. A rule is only applied while the
+/// number of rule applications leading to it doesn't exceed that limit.
+struct Evaluator<'a> {
+    settings: &'a Settings,
+    random: Random<'a>,
+    pending: std::collections::VecDeque<Iterations<'a>>,
+    expanded: Vec<Iterations<'a>>,
+    objects: usize,
+    /// The fewest applications, counting its own, after which each rule can emit a primitive.
+    /// Rules that never can are left out.
+    reach: std::collections::BTreeMap<&'a str, usize>,
+}
+
+impl<'a> Evaluator<'a> {
+    fn new<R: rand::Rng>(rules: &'a RuleSet, ctx_mut: &mut ContextMut<R>) -> Self {
+        let settings = &rules.settings;
+        let seed = settings.seed.unwrap_or_else(|| ctx_mut.rng.gen());
+        let mut evaluator = Self {
+            settings,
+            random: Random::new(seed, &settings.color_pool),
+            pending: Default::default(),
+            expanded: vec![],
+            objects: 0,
+            reach: Self::reach(&rules.rules),
+        };
+        let ctx = Context {
+            seed,
+            ..Context::new(&rules.rules)
+        };
+        evaluator.apply(&rules.top_level, ctx);
+        evaluator
+    }
+
+    fn apply(&mut self, custom: &'a Custom, ctx: Context<'a>) {
+        if ctx.depth > self.settings.max_depth {
+            return;
+        }
+        if self.culled(&ctx.tx) {
+            return;
+        }
+        let mut ctx = ctx;
+        if let Some(max_depth) = custom.rule.max_depth {
+            let name = &custom.rule.name;
+            let remaining = ctx.depths.get(name).copied().unwrap_or(max_depth);
+            if remaining == 0 {
+                if let Some(retirement_rule) = &custom.rule.retirement_rule {
+                    std::rc::Rc::make_mut(&mut ctx.depths).remove(name);
+                    let rule = ctx
+                        .rules
+                        .get(retirement_rule)
+                        .expect("checked by the parser");
+                    let (seed, color_pool) = (ctx.seed, &self.settings.color_pool);
+                    let iterations =
+                        Iterations::new(&[], retirement_rule, rule, ctx, seed, color_pool);
+                    self.pending.push_back(iterations);
+                }
+                return;
+            }
+            std::rc::Rc::make_mut(&mut ctx.depths).insert(name.clone(), remaining - 1);
+        }
+
+        custom.expand(&ctx, &mut self.expanded, &mut self.random);
+        match self.settings.recursion {
+            // pushed in reverse so that actions are popped in the order they were written
+            Recursion::DepthFirst => self.pending.extend(self.expanded.drain(..).rev()),
+            Recursion::BreadthFirst => self.pending.extend(self.expanded.drain(..)),
+        }
+    }
+
+    fn reach(rules: &RulesMap) -> std::collections::BTreeMap<&str, usize> {
+        let mut reach = rules
+            .iter()
+            .filter(|(_, rule)| matches!(rule, Rule::Primitive(..)))
+            .map(|(name, _)| (name.as_str(), 0))
+            .collect::<std::collections::BTreeMap<_, _>>();
+        loop {
+            let mut changed = false;
+            for (name, rule) in rules {
+                let alternatives = match rule {
+                    Rule::Primitive(..) => continue,
+                    Rule::Custom(inner) => std::slice::from_ref(inner),
+                    Rule::Ambiguous(inner) => &inner.actions[..],
+                };
+                let fewest = alternatives
+                    .iter()
+                    .flat_map(Custom::invoked)
+                    .filter_map(|invoked| reach.get(invoked))
+                    .min()
+                    .map(|fewest| fewest + 1);
+                if let Some(fewest) = fewest {
+                    if !matches!(reach.get(name.as_str()), Some(r) if *r <= fewest) {
+                        reach.insert(name.as_str(), fewest);
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                return reach;
+            }
+        }
+    }
+
+    /// Whether none of the invocations could emit a primitive within `Settings::max_depth`,
+    /// however many of them there are.
+    fn fruitless(&self, iterations: &Iterations) -> bool {
+        match (iterations.rule, self.reach.get(iterations.name)) {
+            (Rule::Primitive(..), _) => false,
+            (_, Some(reach)) => iterations.ctx.depth + reach > self.settings.max_depth,
+            (_, None) => true,
+        }
+    }
+
+    /// Whether `tx` is outside `Settings::min_size` and `Settings::max_size`.
+    fn culled(&self, tx: &Transform) -> bool {
+        let size = tx.size();
+        matches!(self.settings.min_size, Some(min_size) if size < min_size)
+            || matches!(self.settings.max_size, Some(max_size) if size > max_size)
+    }
+}
+
+impl<'a> Iterator for Evaluator<'a> {
+    type Item = (Transform, Primitive, Option<&'a Geometry>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if Some(self.objects) == self.settings.max_objects {
+            return None;
+        }
+        loop {
+            let mut iterations = match self.settings.recursion {
+                Recursion::DepthFirst => self.pending.pop_back()?,
+                Recursion::BreadthFirst => self.pending.pop_front()?,
+            };
+            if self.fruitless(&iterations) {
+                continue;
+            }
+            let Some(Invocation { rule, ctx }) = iterations.next() else {
+                continue;
+            };
+            // put back where it came from so the rest of the loop keeps its place in the order
+            if iterations.remaining {
+                match self.settings.recursion {
+                    Recursion::DepthFirst => self.pending.push_back(iterations),
+                    Recursion::BreadthFirst => self.pending.push_front(iterations),
+                }
+            }
+            if self.settings.sync_random {
+                self.random.reseed(ctx.seed);
+            }
+            match rule {
+                Rule::Primitive(..) if self.culled(&ctx.tx) => {}
+                Rule::Primitive(primitive, geometry) => {
+                    if *primitive != Primitive::Template {
+                        self.objects += 1;
+                    }
+                    return Some((ctx.tx, *primitive, geometry.as_ref()));
+                }
+                Rule::Custom(inner) => self.apply(inner, ctx),
+                Rule::Ambiguous(inner) => {
+                    let index = rand::Rng::sample(&mut self.random.rng, &inner.weights);
+                    self.apply(&inner.actions[index], ctx);
+                }
             }
         }
     }
 }
 
+#[derive(Clone)]
 struct Context<'a> {
     tx: Transform,
     depth: usize,
+    /// Remaining applications of each rule with a max depth along this branch.
+    depths: std::rc::Rc<std::collections::BTreeMap<String, usize>>,
+    /// Restores the random stream before this invocation is applied when `set syncrandom` is on.
+    seed: u64,
     rules: &'a RulesMap,
 }
 
@@ -136,30 +436,65 @@ impl<'a> Context<'a> {
         Self {
             tx: Default::default(),
             depth: 0,
+            depths: Default::default(),
+            seed: 0,
             rules,
         }
     }
 
-    fn descend(&self, tx: Transform) -> Self {
+    fn descend(&self, tx: Transform, seed: u64) -> Self {
         Self {
             depth: self.depth + 1,
             tx,
+            depths: self.depths.clone(),
+            seed,
             rules: self.rules,
         }
     }
 }
 
+/// The random choices of one evaluation, which scripts can reseed with `set seed`.
+struct Random<'a> {
+    rng: rand::rngs::SmallRng,
+    initial_seed: u64,
+    color_pool: &'a ColorPool,
+}
+
+impl<'a> Random<'a> {
+    fn new(initial_seed: u64, color_pool: &'a ColorPool) -> Self {
+        Self {
+            rng: rand::SeedableRng::seed_from_u64(initial_seed),
+            initial_seed,
+            color_pool,
+        }
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.rng = rand::SeedableRng::seed_from_u64(seed);
+    }
+
+    fn set(&mut self, action: &SetAction) {
+        match action {
+            SetAction::Seed(seed) => self.reseed(*seed),
+            SetAction::ResetSeed => self.reseed(self.initial_seed),
+            _ => {}
+        }
+    }
+
+    /// A seed for a new invocation.
+    fn seed(&mut self) -> u64 {
+        rand::Rng::gen(&mut self.rng)
+    }
+}
+
+/// Supplies the seed for evaluations of scripts that don't `set seed`.
 pub struct ContextMut<'a, R> {
     rng: &'a mut R,
-    depths: std::collections::BTreeMap<String, usize>,
 }
 
 impl<'a, R> ContextMut<'a, R> {
     pub fn new(rng: &'a mut R) -> Self {
-        Self {
-            rng,
-            depths: Default::default(),
-        }
+        Self { rng }
     }
 }
 
@@ -167,6 +502,8 @@ impl<'a, R> ContextMut<'a, R> {
 pub struct RuleSet {
     top_level: Custom,
     rules: RulesMap,
+    settings: Settings,
+    camera: Option<Camera>,
 }
 
 impl RuleSet {
@@ -183,7 +520,7 @@ impl RuleSet {
             Primitive::Other,
         ]
         .into_iter()
-        .map(|p| (p.name().to_string(), Rule::Primitive(p)))
+        .map(|p| (p.name().to_string(), Rule::Primitive(p, None)))
         .collect();
 
         Self {
@@ -197,41 +534,94 @@ impl RuleSet {
                 actions: vec![],
             },
             rules,
+            settings: Default::default(),
+            camera: None,
         }
     }
 
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn settings_mut(&mut self) -> &mut Settings {
+        &mut self.settings
+    }
+
+    /// The view saved with the script, if it set one.
+    pub fn camera(&self) -> Option<&Camera> {
+        self.camera.as_ref()
+    }
+
     fn add_action(&mut self, action: Action) {
         self.top_level.actions.push(action);
     }
 
-    fn push(&mut self, rule: Rule) {
+    fn camera_mut(&mut self) -> &mut Camera {
+        self.camera.get_or_insert_with(Default::default)
+    }
+
+    fn set(&mut self, action: SetAction) {
+        match action {
+            SetAction::MaxDepth(max_depth) => self.settings.max_depth = max_depth,
+            SetAction::MaxObjects(max_objects) => self.settings.max_objects = Some(max_objects),
+            SetAction::Recursion(recursion) => self.settings.recursion = recursion,
+            SetAction::ColorPool(color_pool) => self.settings.color_pool = color_pool,
+            SetAction::MinSize(min_size) => self.settings.min_size = Some(min_size),
+            SetAction::MaxSize(max_size) => self.settings.max_size = Some(max_size),
+            SetAction::Translation(translation) => self.camera_mut().translation = translation,
+            SetAction::Rotation(rotation) => self.camera_mut().rotation = rotation,
+            SetAction::Pivot(pivot) => self.camera_mut().pivot = pivot,
+            SetAction::Scale(scale) => self.camera_mut().scale = scale,
+            SetAction::Background(background) => self.settings.background = Some(background),
+            SetAction::Seed(seed) => self.settings.seed = Some(seed),
+            SetAction::SyncRandom(sync_random) => self.settings.sync_random = sync_random,
+            SetAction::Namespaced(key, value) => {
+                self.settings.namespaced.insert(key, value);
+            }
+            action => self.add_action(Action::Set(action)),
+        }
+    }
+
+    /// Registers a primitive written inline, like a triangle, under the text of its invocation.
+    fn add_inline_primitive(&mut self, name: &str, primitive: Primitive, geometry: Geometry) {
+        self.rules
+            .entry(name.to_string())
+            .or_insert(Rule::Primitive(primitive, Some(geometry)));
+    }
+
+    /// Stands in for a rule that is invoked but never defined.
+    fn define_empty(&mut self, name: &str) {
+        let rule = RuleDefinition {
+            name: name.to_string(),
+            max_depth: None,
+            retirement_rule: None,
+            weight: 1.0,
+        };
+        self.rules.insert(
+            name.to_string(),
+            Rule::Custom(Custom {
+                rule,
+                actions: vec![],
+            }),
+        );
+    }
+
+    fn push(&mut self, rule: Custom) -> Result<(), ErrorKind> {
         use std::collections::btree_map::Entry;
-        match self.rules.entry(rule.name().to_string()) {
+        match self.rules.entry(rule.rule.name.clone()) {
             Entry::Vacant(entry) => {
-                entry.insert(rule);
+                entry.insert(Rule::Custom(rule));
             }
-            Entry::Occupied(entry) => {
-                fn assert_custom(rule: Rule) -> Custom {
-                    match rule {
-                        Rule::Custom(inner) => inner,
-                        _ => panic!(),
-                    }
+            Entry::Occupied(mut entry) => match entry.get_mut() {
+                Rule::Primitive(..) => return Err(ErrorKind::PrimitiveRedefinition),
+                Rule::Ambiguous(existing) => existing.push(rule)?,
+                Rule::Custom(existing) => {
+                    let ambiguous = Ambiguous::new(vec![existing.clone(), rule])?;
+                    entry.insert(Rule::Ambiguous(ambiguous));
                 }
-
-                let (name, existing) = entry.remove_entry();
-                let actions = vec![assert_custom(existing), assert_custom(rule)];
-                let weights = actions.iter().map(|action| action.rule.weight);
-                let weights = rand_distr::WeightedIndex::new(weights).unwrap();
-                self.rules.insert(
-                    name.clone(),
-                    Rule::Ambiguous(Ambiguous {
-                        name,
-                        actions,
-                        weights,
-                    }),
-                );
-            }
+            },
         }
+        Ok(())
     }
 
     pub fn iter<'a, 'b: 'a, R: rand::Rng>(
@@ -249,7 +639,7 @@ impl Default for RuleSet {
 }
 
 pub struct RuleSetIterator<'a> {
-    iter: Box<dyn Iterator<Item = (Transform, Primitive)> + 'a>,
+    iter: Box<dyn Iterator<Item = (Transform, Primitive, Option<&'a Geometry>)> + 'a>,
 }
 
 impl<'a> RuleSetIterator<'a> {
@@ -257,25 +647,35 @@ impl<'a> RuleSetIterator<'a> {
         rules: &'a RuleSet,
         ctx_mut: &'a mut ContextMut<'b, R>,
     ) -> Self {
-        let iter = rules.top_level.iter(Context::new(&rules.rules), ctx_mut);
+        let iter = Evaluator::new(rules, ctx_mut);
         Self {
             iter: Box::new(iter),
         }
     }
+
+    /// Also yields the [`Geometry`] of primitives written inline, like triangles.
+    pub fn with_geometry(
+        self,
+    ) -> impl Iterator<Item = (Transform, Primitive, Option<&'a Geometry>)> + 'a {
+        self.iter
+    }
 }
 
 impl Iterator for RuleSetIterator<'_> {
     type Item = (Transform, Primitive);
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.iter.next()
+        self.iter
+            .next()
+            .map(|(tx, primitive, _geometry)| (tx, primitive))
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 struct TransformationLoop {
     count: usize,
-    transform: Transform,
+    /// Applied in turn on every iteration.
+    transforms: Vec<Transform>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -285,58 +685,22 @@ struct TransformAction {
 }
 
 impl TransformAction {
-    fn iter(&self, tx: Transform) -> TransformActionIter {
-        let iter = if self.loops.is_empty() {
-            let iter = std::iter::once_with(move || vec![tx]);
-            Box::new(iter) as Box<dyn Iterator<Item = Vec<Transform>>>
-        } else {
-            let iter = self.loops.iter().map(|l| {
-                (1..=l.count).scan(tx, move |state, _| {
-                    *state *= l.transform;
-                    Some(*state)
-                })
-            });
-            Box::new(itertools::Itertools::multi_cartesian_product(iter))
-        };
-        TransformActionIter { iter }
-    }
-
-    fn execute<'a, 'b: 'a, R: rand::Rng>(
+    fn execute<'a>(
         &'a self,
         ctx: &Context<'a>,
-        ctx_mut: &'a mut ContextMut<'b, R>,
-    ) -> Vec<(Transform, Primitive)> {
-        let rule = ctx.rules.get(&self.rule).unwrap();
-        if let Some(max_depth) = rule.max_depth() {
-            if let Some(current) = ctx_mut.depths.get_mut(rule.name()) {
-                *current = current.saturating_sub(1);
-                if *current == 0 {
-                    ctx_mut.depths.remove(rule.name());
-                    return vec![];
-                }
-            } else {
-                ctx_mut
-                    .depths
-                    .insert(rule.name().to_string(), max_depth - 1);
-            }
-        }
-        self.iter(ctx.tx)
-            .flat_map(|tx| rule.iter(ctx.descend(tx), ctx_mut))
-            .collect::<Vec<_>>()
-    }
-}
-
-struct TransformActionIter<'a> {
-    iter: Box<dyn Iterator<Item = Vec<Transform>> + 'a>,
-}
-
-impl Iterator for TransformActionIter<'_> {
-    type Item = Transform;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        self.iter
-            .next()
-            .and_then(|txs| txs.into_iter().reduce(|acc, tx| acc * tx))
+        pending: &mut Vec<Iterations<'a>>,
+        random: &mut Random<'a>,
+    ) {
+        let rule = ctx.rules.get(&self.rule).expect("checked by the parser");
+        let seed = random.seed();
+        pending.push(Iterations::new(
+            &self.loops,
+            &self.rule,
+            rule,
+            ctx.clone(),
+            seed,
+            random.color_pool,
+        ));
     }
 }
 
@@ -345,11 +709,19 @@ impl Iterator for TransformActionIter<'_> {
 enum SetAction {
     MaxDepth(usize),
     MaxObjects(usize),
+    Recursion(Recursion),
     MinSize(f32),
     MaxSize(f32),
-    Seed(usize),
+    ColorPool(ColorPool),
+    Translation([f32; 3]),
+    Rotation([f32; 9]),
+    Pivot([f32; 3]),
+    Scale(f32),
+    Seed(u64),
     ResetSeed,
-    Background(String),
+    SyncRandom(bool),
+    Namespaced(String, Setting),
+    Background(Color),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -368,16 +740,21 @@ mod tests {
             loops: vec![
                 TransformationLoop {
                     count: 2,
-                    transform: Transform::translation(2., 0., 0.),
+                    transforms: vec![Transform::translation(2., 0., 0.)],
                 },
                 TransformationLoop {
                     count: 2,
-                    transform: Transform::translation(0., 2., 0.),
+                    transforms: vec![Transform::translation(0., 2., 0.)],
                 },
             ],
             rule: "".to_string(),
         };
-        let mut cmds = action.iter(Transform::default());
+        let rules = RulesMap::new();
+        let rule = Rule::Primitive(Primitive::Box, None);
+        let color_pool = ColorPool::default();
+        let ctx = Context::new(&rules);
+        let mut cmds = Iterations::new(&action.loops, "box", &rule, ctx, 0, &color_pool)
+            .map(|invocation| invocation.ctx.tx);
 
         assert_eq!(cmds.next(), Some(Transform::translation(2., 2., 0.)));
         assert_eq!(cmds.next(), Some(Transform::translation(2., 4., 0.)));
@@ -452,6 +829,54 @@ mod tests {
         assert_eq!(cmds.next(), None);
     }
 
+    #[test]
+    fn reflection() {
+        let parser = Parser::new(crate::Lexer::new("{ x 2 fx } box { fy fy } box"))
+            .rules()
+            .unwrap();
+        let mut rng = rand::thread_rng();
+        let mut ctx = ContextMut::new(&mut rng);
+        let mut cmds = parser.iter(&mut ctx).map(|(tx, _primitive)| tx);
+
+        let tx = Transform::translation(2., 0., 0.) * Transform::reflect_x();
+        assert_eq!(cmds.next(), Some(tx));
+        let corner = mint::ColumnMatrix4::from(tx).w;
+        assert_eq!([corner.x, corner.y, corner.z], [3., 0., 0.]);
+        assert_eq!(cmds.next(), Some(Transform::default()));
+        assert_eq!(cmds.next(), None);
+    }
+
+    #[test]
+    fn matrix() {
+        let source = "{ m 1 0 0 0 1 0 0 0 1 } box { matrix 0 -1 0 1 0 0 0 0 1 } box { m 2 0 0 0 2 0 0 0 2 } box";
+        let parser = Parser::new(crate::Lexer::new(source)).rules().unwrap();
+        let mut rng = rand::thread_rng();
+        let mut ctx = ContextMut::new(&mut rng);
+        let mut cmds = parser.iter(&mut ctx).map(|(tx, _primitive)| tx);
+
+        assert_eq!(cmds.next(), Some(Transform::default()));
+        approx::assert_abs_diff_eq!(
+            cmds.next().unwrap(),
+            Transform::rotate_z(90.),
+            epsilon = 0.0001
+        );
+        assert_eq!(cmds.next(), Some(Transform::scale(2., 2., 2.)));
+        assert_eq!(cmds.next(), None);
+
+        let source = "{ m 1 0 0 0 1 0 0 0 } box";
+        let err = Parser::new(crate::Lexer::new(source)).rules().unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::ExpectedNumber));
+
+        // outside of transforms `m` is still a rule name
+        let source = "{ m -1 0 0 0 1 0 0 0 1 } m rule m { box }";
+        let parser = Parser::new(crate::Lexer::new(source)).rules().unwrap();
+        let mut rng = rand::thread_rng();
+        let mut ctx = ContextMut::new(&mut rng);
+        let mut cmds = parser.iter(&mut ctx);
+        assert_eq!(cmds.next(), Some((Transform::reflect_x(), Primitive::Box)));
+        assert_eq!(cmds.next(), None);
+    }
+
     #[test]
     fn transform_stack() {
         let parser = Parser::new(crate::Lexer::new("{ x 1 } r1 rule r1 { box }"))
@@ -520,32 +945,581 @@ mod tests {
             action1.loops,
             vec![TransformationLoop {
                 count: 1,
-                transform: Transform::translation(1., 0., 0.) * Transform::hsv(40., 1., 1.)
+                transforms: vec![Transform::translation(1., 0., 0.) * Transform::hsv(40., 1., 1.)]
             }]
         );
-        let result = action1.execute(&Context::new(&parser.rules), &mut ctx);
-        assert_eq!(result.len(), 1);
+        let color_pool = ColorPool::default();
+        let mut random = Random::new(0, &color_pool);
+        let mut pending = vec![];
+        action1.execute(&Context::new(&parser.rules), &mut pending, &mut random);
+        assert_eq!(pending.len(), 1);
+
+        let mut pending = vec![];
+        rule.expand(&Context::new(&parser.rules), &mut pending, &mut random);
+        assert_eq!(pending.len(), 2);
+
+        let cmds = parser
+            .iter(&mut ctx)
+            .map(|(tx, _primitive)| tx)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            cmds,
+            vec![
+                Transform::translation(0., 1., 0.) * Transform::hsv(40., 1., 1.),
+                Transform::translation(0., 2., 0.) * Transform::hsv(80., 1., 1.),
+            ]
+        );
+    }
 
-        let result = rule
-            .actions
-            .iter()
-            .filter_map(filter)
-            .flat_map(|action| action.execute(&Context::new(&parser.rules), &mut ctx))
-            .count();
-        assert_eq!(result, 2);
+    #[test]
+    fn retirement_rule() {
+        let parser = Parser::new(crate::Lexer::new(
+            "r1
+            rule r1 md 3 > end {
+                box
+                { x 1 } r1
+            }
+            rule end { sphere }",
+        ))
+        .rules()
+        .unwrap();
+        let mut rng = rand::thread_rng();
+        let mut ctx = ContextMut::new(&mut rng);
+        let mut cmds = parser.iter(&mut ctx);
+
+        for x in 0..3 {
+            assert_eq!(
+                cmds.next(),
+                Some((Transform::translation(x as f32, 0., 0.), Primitive::Box))
+            );
+        }
+        assert_eq!(
+            cmds.next(),
+            Some((Transform::translation(3., 0., 0.), Primitive::Sphere))
+        );
+        assert_eq!(cmds.next(), None);
+    }
+
+    #[test]
+    fn global_max_depth() {
+        let source = "r1 rule r1 { box { x 1 } r1 }";
+        let mut rules = Parser::new(crate::Lexer::new(source)).rules().unwrap();
+        let mut rng = rand::thread_rng();
+        let mut ctx = ContextMut::new(&mut rng);
+        assert_eq!(rules.iter(&mut ctx).count(), 1000);
+
+        let source = format!("set maxdepth 10 {}", source);
+        let rules_with_max = Parser::new(crate::Lexer::new(&source)).rules().unwrap();
+        assert_eq!(rules_with_max.settings().max_depth, 10);
+        assert_eq!(rules_with_max.iter(&mut ctx).count(), 10);
+
+        rules.settings_mut().max_depth = 100_000;
+        assert_eq!(rules.iter(&mut ctx).count(), 100_000);
+    }
+
+    #[test]
+    fn max_objects() {
+        let source = "set maxobjects 5 r1 rule r1 { box { x 1 } r1 }";
+        let mut rules = Parser::new(crate::Lexer::new(source)).rules().unwrap();
+        assert_eq!(rules.settings().max_objects, Some(5));
+
+        let mut rng = rand::thread_rng();
+        let mut ctx = ContextMut::new(&mut rng);
+        let mut evaluator = Evaluator::new(&rules, &mut ctx);
+        assert_eq!(evaluator.by_ref().count(), 5);
+        assert!(evaluator.pending.len() <= 1);
+
+        rules.settings_mut().max_objects = None;
+        assert_eq!(rules.iter(&mut ctx).count(), 1000);
+
+        for (source, count) in [
+            ("set maxobjects 1 100000000000 * { x 1 } box", 1),
+            (
+                "set maxobjects 3 10 * { x 1 } 4000000000000000000 * { y 1 } box",
+                3,
+            ),
+            (
+                "set maxobjects 3 set recursion depth 100000000000 * { x 1 } box",
+                3,
+            ),
+            (
+                "set maxobjects 3 set maxdepth 3 set recursion depth
+                r1 rule r1 { 100000000000 * { x 1 } r1 box }",
+                3,
+            ),
+            ("set maxobjects 1 100000000000 * { x 1 } r1 box rule r1 { }", 1),
+        ] {
+            let rules = Parser::new(crate::Lexer::new(source)).rules().unwrap();
+            assert_eq!(rules.iter(&mut ctx).count(), count);
+        }
+    }
+
+    #[test]
+    fn size_limits() {
+        let source = "set minsize 0.2 r1 rule r1 { box { s 0.5 } r1 }";
+        let rules = Parser::new(crate::Lexer::new(source)).rules().unwrap();
+        let mut rng = rand::thread_rng();
+        let mut ctx = ContextMut::new(&mut rng);
+        let sizes = rules
+            .iter(&mut ctx)
+            .map(|(tx, _primitive)| tx.size())
+            .collect::<Vec<_>>();
+        assert_eq!(sizes.len(), 4);
+        assert!(sizes.iter().all(|size| *size >= 0.2));
+
+        let source = "set maxsize 10 r1 rule r1 { box { s 2 } r1 }";
+        let rules = Parser::new(crate::Lexer::new(source)).rules().unwrap();
+        assert_eq!(rules.iter(&mut ctx).count(), 3);
+
+        for source in [
+            "set minsize 1 { s 0.1 } box",
+            "set maxsize 2 { s 10 } sphere",
+        ] {
+            let rules = Parser::new(crate::Lexer::new(source)).rules().unwrap();
+            assert_eq!(rules.iter(&mut ctx).count(), 0);
+        }
+    }
+
+    #[test]
+    fn per_branch_depth() {
+        let parser = Parser::new(crate::Lexer::new(
+            "r1
+            rule r1 md 2 {
+                box
+                { x 1 } r2
+                { y 1 } r2
+            }
+            rule r2 { r1 }",
+        ))
+        .rules()
+        .unwrap();
+        let mut rng = rand::thread_rng();
+        let mut ctx = ContextMut::new(&mut rng);
+        let cmds = parser
+            .iter(&mut ctx)
+            .map(|(tx, _primitive)| tx)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            cmds,
+            vec![
+                Transform::default(),
+                Transform::translation(1., 0., 0.),
+                Transform::translation(0., 1., 0.),
+            ]
+        );
+    }
+
+    #[test]
+    fn recursion_order() {
+        let source = "r1 rule r1 md 3 { { x 1 } r1 box { y 1 } r1 }";
+        let mut rules = Parser::new(crate::Lexer::new(source)).rules().unwrap();
+        assert_eq!(rules.settings().recursion, Recursion::BreadthFirst);
+
+        let mut rng = rand::thread_rng();
+        let mut ctx = ContextMut::new(&mut rng);
+        let bfs = rules
+            .iter(&mut ctx)
+            .map(|(tx, _primitive)| tx)
+            .collect::<Vec<_>>();
+        assert_eq!(bfs[0], Transform::default());
+        assert_eq!(bfs[1], Transform::translation(1., 0., 0.));
+        assert_eq!(bfs[2], Transform::translation(0., 1., 0.));
+        assert_eq!(bfs.len(), 7);
+
+        rules.settings_mut().recursion = Recursion::DepthFirst;
+        let dfs = rules
+            .iter(&mut ctx)
+            .map(|(tx, _primitive)| tx)
+            .collect::<Vec<_>>();
+        assert_eq!(dfs[0], Transform::translation(2., 0., 0.));
+        assert_eq!(dfs[1], Transform::translation(1., 0., 0.));
+        assert_eq!(dfs.len(), bfs.len());
+
+        let source = format!("set recursion depth {}", source);
+        let rules = Parser::new(crate::Lexer::new(&source)).rules().unwrap();
+        assert_eq!(rules.settings().recursion, Recursion::DepthFirst);
+    }
+
+    #[test]
+    fn many_ambiguous_definitions() {
+        let rules = Parser::new(crate::Lexer::new(
+            "1000 * { } r
+            rule r { box }
+            rule r { sphere }
+            rule r w 2 { dot }",
+        ))
+        .rules()
+        .unwrap();
+
+        match rules.rules.get("r").unwrap() {
+            Rule::Ambiguous(inner) => assert_eq!(inner.actions.len(), 3),
+            _ => panic!(),
+        }
+
+        let mut rng: rand::rngs::SmallRng = rand::SeedableRng::seed_from_u64(0);
+        let mut ctx = ContextMut::new(&mut rng);
+        let mut counts = std::collections::BTreeMap::new();
+        for (_tx, primitive) in rules.iter(&mut ctx) {
+            *counts.entry(primitive).or_insert(0) += 1;
+        }
+        assert_eq!(counts.len(), 3);
+        assert!(counts[&Primitive::Dot] > counts[&Primitive::Box]);
+        assert!(counts[&Primitive::Dot] > counts[&Primitive::Sphere]);
+    }
+
+    #[test]
+    fn triangles() {
+        let source = "{ x 1 } triangle[0,0,0;1,0,0;0.5,0.5,0.5] rule r { triangle[0,0,0;1,0,0;0.5,0.5,0.5] 2 * { y 1 } triangle[0, 1, 0; 0, 0, 0; 0.5, 0.5, 0.5] } r";
+        let rules = Parser::new(crate::Lexer::new(source)).rules().unwrap();
+        let mut rng = rand::thread_rng();
+        let mut ctx = ContextMut::new(&mut rng);
+        let cmds = rules.iter(&mut ctx).with_geometry().collect::<Vec<_>>();
+
+        let pyramid = Geometry::Triangle([[0., 0., 0.], [1., 0., 0.], [0.5, 0.5, 0.5]]);
+        let side = Geometry::Triangle([[0., 1., 0.], [0., 0., 0.], [0.5, 0.5, 0.5]]);
+        let triangle = |tx, geometry| (tx, Primitive::Triangle, Some(geometry));
+        assert_eq!(
+            cmds,
+            vec![
+                triangle(Transform::translation(1., 0., 0.), &pyramid),
+                triangle(Transform::default(), &pyramid),
+                triangle(Transform::translation(0., 1., 0.), &side),
+                triangle(Transform::translation(0., 2., 0.), &side),
+            ]
+        );
+
+        let err = Parser::new(crate::Lexer::new("triangle[0,0,0;1,0,0]"))
+            .rules()
+            .unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::InvalidTriangle));
+    }
+
+    #[test]
+    fn template_markers() {
+        let source = "set recursion depth
+            template::union-begin r1 template::union-end
+            rule r1 { template::difference-begin box { x 1 } sphere template::difference-end }";
+        let rules = Parser::new(crate::Lexer::new(source)).rules().unwrap();
+        let mut rng = rand::thread_rng();
+        let mut ctx = ContextMut::new(&mut rng);
+        let cmds = rules
+            .iter(&mut ctx)
+            .with_geometry()
+            .map(|(tx, primitive, geometry)| (tx, primitive, geometry.cloned()))
+            .collect::<Vec<_>>();
+
+        let marker = |name: &str| {
+            let geometry = Geometry::Template(name.to_string());
+            (Transform::default(), Primitive::Template, Some(geometry))
+        };
+        assert_eq!(
+            cmds,
+            vec![
+                marker("union-begin"),
+                marker("difference-begin"),
+                (Transform::default(), Primitive::Box, None),
+                (Transform::translation(1., 0., 0.), Primitive::Sphere, None),
+                marker("difference-end"),
+                marker("union-end"),
+            ]
+        );
 
+        // markers aren't objects
+        let source = "set maxobjects 3 template::union-begin 3 * { x 1 } box template::union-end";
+        let rules = Parser::new(crate::Lexer::new(source)).rules().unwrap();
+        let primitives = rules
+            .iter(&mut ctx)
+            .map(|(_tx, primitive)| primitive)
+            .collect::<Vec<_>>();
         assert_eq!(
-            parser
-                .top_level
-                .iter(Context::new(&parser.rules), &mut ctx)
-                .count(),
-            4
+            primitives,
+            [
+                Primitive::Template,
+                Primitive::Box,
+                Primitive::Box,
+                Primitive::Box
+            ]
+        );
+    }
+
+    #[test]
+    fn camera() {
+        let rules = Parser::new(crate::Lexer::new("box")).rules().unwrap();
+        assert_eq!(rules.camera(), None);
+
+        let source = "set translation [-8.07939 -6.42262 -20]
+            set rotation [0 -1 0 1 0 0 0 0 1]
+            set pivot [0 0 1.5]
+            set scale 0.625595
+            box";
+        let rules = Parser::new(crate::Lexer::new(source)).rules().unwrap();
+        let camera = Camera {
+            translation: [-8.07939, -6.42262, -20.],
+            rotation: [0., -1., 0., 1., 0., 0., 0., 0., 1.],
+            pivot: [0., 0., 1.5],
+            scale: 0.625595,
+        };
+        assert_eq!(rules.camera(), Some(&camera));
+
+        let view = nalgebra::Matrix4::from(camera.view());
+        let pivot = view.transform_point(&nalgebra::Point3::new(0., 0., 1.5));
+        assert_eq!(pivot, nalgebra::Point3::new(-8.07939, -6.42262, -20.));
+        let x = view.transform_vector(&nalgebra::Vector3::x());
+        approx::assert_abs_diff_eq!(x, nalgebra::Vector3::y() * 0.625595);
+
+        let source = "set rotation [1 0 0 0 1 0 0 0] box";
+        let err = Parser::new(crate::Lexer::new(source)).rules().unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::ExpectedVector));
+    }
+
+    #[test]
+    fn background() {
+        let rules = Parser::new(crate::Lexer::new("box")).rules().unwrap();
+        assert_eq!(rules.settings().background, None);
+
+        let rules = Parser::new(crate::Lexer::new("set background #a00 box"))
+            .rules()
+            .unwrap();
+        assert_eq!(rules.settings().background, Color::parse("#aa0000"));
+
+        let rules = Parser::new(crate::Lexer::new("set background white box"))
+            .rules()
+            .unwrap();
+        assert_eq!(rules.settings().background, Some(Color::new(1., 1., 1.)));
+
+        let err = Parser::new(crate::Lexer::new("set background notacolor"))
+            .rules()
+            .unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::InvalidColor));
+    }
+
+    #[test]
+    fn seeds() {
+        let positions = |source: &str, seed: u64| {
+            let rules = Parser::new(crate::Lexer::new(source)).rules().unwrap();
+            let mut rng: rand::rngs::SmallRng = rand::SeedableRng::seed_from_u64(seed);
+            let mut ctx = ContextMut::new(&mut rng);
+            rules
+                .iter(&mut ctx)
+                .map(|(tx, _primitive)| mint::ColumnMatrix4::from(tx).w.y)
+                .collect::<Vec<_>>()
+        };
+
+        let rules = "rule r { { y 1 } r box } rule r { { y 2 } r box }";
+        let script = format!("set maxdepth 20 set seed 14 r {}", rules);
+        assert_eq!(positions(&script, 1), positions(&script, 2));
+        let reseeded = format!("set maxdepth 20 set seed 15 r {}", rules);
+        assert_ne!(positions(&script, 1), positions(&reseeded, 1));
+
+        // both branches restart the stream from the initial seed, so they grow the same way
+        let branches = format!(
+            "set syncrandom true set maxdepth 10 {{ x 1 }} start {{ x -1 }} start rule start {{ set seed initial r }} {}",
+            rules
         );
+        let rules = Parser::new(crate::Lexer::new(&branches)).rules().unwrap();
+        let mut rng = rand::thread_rng();
+        let mut ctx = ContextMut::new(&mut rng);
+        let (left, right): (Vec<_>, Vec<_>) = rules
+            .iter(&mut ctx)
+            .map(|(tx, _primitive)| mint::ColumnMatrix4::from(tx).w)
+            .partition(|w| w.x > 0.);
+        let heights = |w: Vec<mint::Vector4<f32>>| w.iter().map(|w| w.y).collect::<Vec<_>>();
+        assert!(!left.is_empty());
+        assert_eq!(heights(left), heights(right));
+
+        // with syncrandom, choices no longer depend on the order rules are applied in
+        let script = "set maxdepth 8 3 * { x 1 } r rule r { { y 1 } r box } rule r { { y 2 } 2 * { z 1 } r box }";
+        let sorted = |recursion: &str, sync_random: bool| {
+            let source = format!(
+                "set recursion {} set syncrandom {} {}",
+                recursion, sync_random, script
+            );
+            let rules = Parser::new(crate::Lexer::new(&source)).rules().unwrap();
+            let mut rng: rand::rngs::SmallRng = rand::SeedableRng::seed_from_u64(3);
+            let mut ctx = ContextMut::new(&mut rng);
+            let mut positions = rules
+                .iter(&mut ctx)
+                .map(|(tx, _primitive)| {
+                    let w = mint::ColumnMatrix4::from(tx).w;
+                    [w.x, w.y, w.z]
+                })
+                .collect::<Vec<_>>();
+            positions.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            positions
+        };
+        assert_eq!(sorted("depth", true), sorted("breadth", true));
+        assert_ne!(sorted("depth", false), sorted("breadth", false));
+    }
+
+    #[test]
+    fn namespaced_settings() {
+        let source = "set raytracer::light [0,0,5]
+            set raytracer::dof [0.245, 0.09]
+            set raytracer::shadows false
+            set raytracer::max-depth 5
+            set raytracer::shiny::reflection 0.3
+            box";
+        let rules = Parser::new(crate::Lexer::new(source)).rules().unwrap();
+        let expected = [
+            ("raytracer::light", Setting::Vector(vec![0., 0., 5.])),
+            ("raytracer::dof", Setting::Vector(vec![0.245, 0.09])),
+            ("raytracer::shadows", Setting::Bool(false)),
+            ("raytracer::max-depth", Setting::Number(5.)),
+            ("raytracer::shiny::reflection", Setting::Number(0.3)),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value))
+        .collect();
+        assert_eq!(rules.settings().namespaced, expected);
+
+        let err = Parser::new(crate::Lexer::new("set raytracer::shadows maybe"))
+            .rules()
+            .unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::ExpectedSettingValue));
+    }
+
+    #[test]
+    fn absolute_color() {
+        let parser = Parser::new(crate::Lexer::new(
+            "{ h 30 b 0.5 } r1
+            rule r1 {
+                { color #0a0 } box
+                { b 0.5 color white } box
+                { color white b 0.5 } box
+                { h 20 } box
+            }",
+        ))
+        .rules()
+        .unwrap();
+        let mut rng = rand::thread_rng();
+        let mut ctx = ContextMut::new(&mut rng);
+        let colors = parser
+            .iter(&mut ctx)
+            .map(|(tx, _primitive)| [tx.hue, tx.sat, tx.brightness])
+            .collect::<Vec<_>>();
+
+        assert_eq!(colors[0], [120., 1., 2. / 3.]);
+        assert_eq!(colors[1], [0., 0., 1.]);
+        assert_eq!(colors[2], [0., 0., 0.5]);
+        assert_eq!(colors[3], [50., 1., 0.5]);
+
+        let err = Parser::new(crate::Lexer::new("{ color nope } box"))
+            .rules()
+            .unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::InvalidColor));
+    }
+
+    #[test]
+    fn blend() {
+        let parser = Parser::new(crate::Lexer::new(
+            "{ color red } r1
+            rule r1 {
+                { blend blue 1 } box
+                { blend blue 1 blend blue 1 } box
+                { blend blue 3 b 0.5 } box
+            }",
+        ))
+        .rules()
+        .unwrap();
+        let mut rng = rand::thread_rng();
+        let mut ctx = ContextMut::new(&mut rng);
+        let colors = parser
+            .iter(&mut ctx)
+            .map(|(tx, _primitive)| [tx.hue, tx.sat, tx.brightness])
+            .collect::<Vec<_>>();
+
+        assert_eq!(colors[0], [120., 1., 1.]);
+        assert_eq!(colors[1], [180., 1., 1.]);
+        assert_eq!(colors[2], [180., 1., 0.5]);
+
+        let drift = Transform::blend(Color::new(0., 0., 1.), 0.04);
+        let nested = Transform::color(Color::new(1., 0., 0.)) * (drift * drift);
+        let sequential = Transform::color(Color::new(1., 0., 0.)) * drift * drift;
+        approx::assert_abs_diff_eq!(nested, sequential, epsilon = 0.0001);
+
+        let hue = |source| {
+            let rules = Parser::new(crate::Lexer::new(source)).rules().unwrap();
+            let mut rng = rand::thread_rng();
+            let mut ctx = ContextMut::new(&mut rng);
+            let hue = rules.iter(&mut ctx).next().unwrap().0.hue;
+            hue
+        };
+        assert_eq!(hue("{ h 350 blend red 1 } box"), 175.);
+        assert_eq!(hue("{ h 710 blend red 1 } box"), 175.);
+        assert_eq!(hue("{ h -10 } r1 rule r1 { { blend red 1 } box }"), 175.);
+        assert_eq!(hue("{ h 20 } r1 rule r1 { { h 350 blend red 1 } box }"), 5.);
+        assert_eq!(
+            hue("{ h 20 } r1 rule r1 { { h 350 } r2 } rule r2 { { blend red 1 } box }"),
+            5.
+        );
+
+        let err = Parser::new(crate::Lexer::new("{ blend red -1 } box"))
+            .rules()
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidBlendStrength);
+    }
+
+    #[test]
+    fn nested_loops_apply_base_once() {
+        let parser = Parser::new(crate::Lexer::new(
+            "{ x 1 b 0.5 } r1 rule r1 { 2 * { y 1 } 2 * { z 1 } box }",
+        ))
+        .rules()
+        .unwrap();
+        let mut rng = rand::thread_rng();
+        let mut ctx = ContextMut::new(&mut rng);
+        let cmds = parser
+            .iter(&mut ctx)
+            .map(|(tx, _primitive)| tx)
+            .collect::<Vec<_>>();
+
+        let base = Transform::translation(1., 0., 0.) * Transform::hsv(0., 1., 0.5);
+        assert_eq!(cmds.len(), 4);
+        assert_eq!(cmds[0], base * Transform::translation(0., 1., 1.));
+        assert_eq!(cmds[3], base * Transform::translation(0., 2., 2.));
+    }
+
+    #[test]
+    fn random_colors() {
+        let source = "set colorpool list:red,orange,white
+            10 * { x 1 } { color random b 0.5 } box";
+        let rules = Parser::new(crate::Lexer::new(source)).rules().unwrap();
+        let pool = ["red", "orange", "white"].map(|name| Color::from_name(name).unwrap());
+        assert_eq!(rules.settings().color_pool, ColorPool::List(pool.to_vec()));
+
+        let colors = |seed| {
+            let mut rng: rand::rngs::SmallRng = rand::SeedableRng::seed_from_u64(seed);
+            let mut ctx = ContextMut::new(&mut rng);
+            rules
+                .iter(&mut ctx)
+                .map(|(tx, _primitive)| [tx.hue, tx.sat, tx.brightness])
+                .collect::<Vec<_>>()
+        };
+        let first = colors(1);
+        assert_eq!(first, colors(1));
+        assert_ne!(first, colors(2));
+        for [hue, sat, brightness] in first {
+            let color = Color::from_hsv(hue, sat, brightness * 2.);
+            assert!(pool.iter().any(|c| {
+                (c.r - color.r).abs() < 0.0001
+                    && (c.g - color.g).abs() < 0.0001
+                    && (c.b - color.b).abs() < 0.0001
+            }));
+        }
+
+        let source = "100 * { color random } box";
+        let rules = Parser::new(crate::Lexer::new(source)).rules().unwrap();
+        let mut rng = rand::thread_rng();
+        let mut ctx = ContextMut::new(&mut rng);
+        assert!(rules
+            .iter(&mut ctx)
+            .all(|(tx, _primitive)| tx.sat == 1. && tx.brightness == 1.));
     }
 
     #[test]
     fn custom_rule_lookup() {
-        const INPUT: &'static str = r#"
+        const INPUT: &str = r#"
 3 * { x 2 h 40 } 2 * { y 2 h 40 } 4 * { z 2 h 40 } r1
 
 rule r1 {
@@ -1,6 +1,6 @@
 use crate::lexer::Token;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ErrorKind {
     UnexpectedEOF,
     ExpectedIdentifier,
@@ -8,6 +8,18 @@ pub enum ErrorKind {
     UnexpectedTransformToken,
     UnexpectedTopLevelToken,
     UnexpectedRuleDefinitionToken,
+    PrimitiveRedefinition,
+    InvalidColor,
+    InvalidBlendStrength,
+    InvalidColorPool,
+    InvalidTriangle,
+    ExpectedVector,
+    ExpectedSettingValue,
+    ExpectedMultiply,
+    ExpectedOpeningBrace,
+    ExpectedClosingBrace,
+    UndefinedRule,
+    InvalidWeight,
 }
 
 impl std::fmt::Display for ErrorKind {
@@ -21,30 +33,147 @@ impl std::fmt::Display for ErrorKind {
             ErrorKind::UnexpectedRuleDefinitionToken => {
                 write!(f, "Unexpected rule definition token.")
             }
+            ErrorKind::PrimitiveRedefinition => write!(f, "Primitives can't be redefined."),
+            ErrorKind::InvalidColor => write!(f, "Expected a color name or hex code."),
+            ErrorKind::InvalidBlendStrength => write!(f, "Blend strengths must not be negative."),
+            ErrorKind::InvalidTriangle => {
+                write!(
+                    f,
+                    "Expected three vertices like triangle[0,0,0;1,0,0;0,1,0]."
+                )
+            }
+            ErrorKind::ExpectedVector => {
+                write!(f, "Expected numbers in square brackets, like [0 1 0].")
+            }
+            ErrorKind::ExpectedSettingValue => {
+                write!(f, "Expected a number, true, false or a vector.")
+            }
+            ErrorKind::ExpectedMultiply => write!(f, "Expected `*` after a loop count."),
+            ErrorKind::ExpectedOpeningBrace => write!(f, "Expected `{{`."),
+            ErrorKind::ExpectedClosingBrace => write!(f, "Expected `}}` to end the rule."),
+            ErrorKind::UndefinedRule => write!(f, "No rule or primitive has this name."),
+            ErrorKind::InvalidWeight => write!(
+                f,
+                "Rule weights must be finite, not negative and not all zero."
+            ),
+            ErrorKind::InvalidColorPool => write!(
+                f,
+                "Expected randomhue, randomrgb, greyscale, list:colors or image:file."
+            ),
         }
     }
 }
 
-#[derive(Clone)]
-pub struct Error<'source> {
-    pub lexer: crate::Lexer<'source>,
+impl ErrorKind {
+    /// A short description of what the parser was looking for.
+    pub fn expected(&self) -> &'static str {
+        match self {
+            ErrorKind::UnexpectedEOF => "the rest of the script",
+            ErrorKind::ExpectedIdentifier => "a rule name",
+            ErrorKind::ExpectedNumber => "a number",
+            ErrorKind::UnexpectedTransformToken => "a transformation or `}`",
+            ErrorKind::UnexpectedTopLevelToken => "a rule, an action or `set`",
+            ErrorKind::UnexpectedRuleDefinitionToken => "`md`, `w` or `{`",
+            ErrorKind::PrimitiveRedefinition => "a name other than a primitive's",
+            ErrorKind::InvalidColor => "a color name or hex code",
+            ErrorKind::InvalidBlendStrength => "a non-negative blend strength",
+            ErrorKind::InvalidColorPool => "a color pool",
+            ErrorKind::InvalidTriangle => "three vertices of three coordinates",
+            ErrorKind::ExpectedVector => "numbers in square brackets",
+            ErrorKind::ExpectedSettingValue => "a number, boolean or vector",
+            ErrorKind::ExpectedMultiply => "`*` after a loop count",
+            ErrorKind::ExpectedOpeningBrace => "`{`",
+            ErrorKind::ExpectedClosingBrace => "an action or `}`",
+            ErrorKind::UndefinedRule => "the name of a defined rule or primitive",
+            ErrorKind::InvalidWeight => "finite, non-negative weights that aren't all zero",
+        }
+    }
+}
+
+/// A parse failure, independent of the source it came from.
+#[derive(Clone, PartialEq)]
+pub struct Error {
     pub kind: ErrorKind,
+    /// The location of the error in the original, unpreprocessed source.
+    pub span: std::ops::Range<usize>,
+    /// The line `span` starts on, counting from one.
+    pub line: usize,
+    /// The character `span` starts at within its line, counting from one.
+    pub column: usize,
+    /// The text of the offending line.
+    pub source_line: String,
+    /// The offending text, or an empty string at the end of the script.
+    pub found: String,
 }
 
-impl std::fmt::Debug for Error<'_> {
+impl Error {
+    fn new(kind: ErrorKind, span: std::ops::Range<usize>, original: &str) -> Self {
+        let before = &original[..span.start];
+        let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+        let line_end = original[span.start..]
+            .find('\n')
+            .map_or(original.len(), |index| span.start + index);
+        Self {
+            kind,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            source_line: original[line_start..line_end].trim_end().to_string(),
+            found: original[span.clone()].to_string(),
+            span,
+        }
+    }
+}
+
+impl std::fmt::Debug for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self)
     }
 }
 
-impl std::fmt::Display for Error<'_> {
+/// Renders the error with the offending line and a caret under the problem, e.g.
+///
+/// ```text
+/// Expected a number.
+///  --> 1:9
+///   |
+/// 1 | { x 1 s q } box
+///   |         ^ expected a number, found `q`
+/// ```
+impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let l = &self.lexer;
-        write!(f, "{} {:?} => {}", self.kind, l.span(), l.slice())
+        let number = self.line.to_string();
+        let gutter = " ".repeat(number.len());
+        // keep tabs so the caret lines up however the terminal renders them
+        let indent = self
+            .source_line
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+        let found = self.found.lines().next().unwrap_or_default();
+        let width = found.chars().count().max(1);
+
+        writeln!(f, "{}", self.kind)?;
+        writeln!(f, "{}--> {}:{}", gutter, self.line, self.column)?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", number, self.source_line)?;
+        write!(
+            f,
+            "{} | {}{} expected {}, ",
+            gutter,
+            indent,
+            "^".repeat(width),
+            self.kind.expected()
+        )?;
+        if found.is_empty() {
+            write!(f, "found the end of the script")
+        } else {
+            write!(f, "found `{}`", found)
+        }
     }
 }
 
-impl std::error::Error for Error<'_> {}
+impl std::error::Error for Error {}
 
 impl From<std::num::ParseIntError> for ErrorKind {
     fn from(_err: std::num::ParseIntError) -> Self {
@@ -61,16 +190,60 @@ impl From<std::num::ParseFloatError> for ErrorKind {
 #[derive(Clone)]
 pub struct Parser<'source> {
     lexer: crate::Lexer<'source>,
+    source_map: Option<&'source crate::SourceMap>,
+    original: &'source str,
 }
 
 impl<'source> Parser<'source> {
     pub fn new(lexer: crate::Lexer<'source>) -> Self {
-        Self { lexer }
+        Self {
+            original: lexer.source(),
+            lexer,
+            source_map: None,
+        }
+    }
+
+    /// Creates a parser over preprocessed text whose error spans are mapped back through
+    /// `source_map` into `original`.
+    pub fn with_source_map(
+        lexer: crate::Lexer<'source>,
+        source_map: &'source crate::SourceMap,
+        original: &'source str,
+    ) -> Self {
+        Self {
+            lexer,
+            source_map: Some(source_map),
+            original,
+        }
+    }
+
+    pub fn rules(&self) -> Result<crate::RuleSet, Error> {
+        let mut lexer = self.lexer.clone();
+        build_rules(&mut lexer, None).map_err(|kind| self.error(kind, lexer.span()))
     }
 
-    pub fn rules(&self) -> Result<crate::RuleSet, Error<'source>> {
+    /// Parses as much of the script as possible, reporting every error rather than the first.
+    ///
+    /// Rules and actions containing errors are left out and undefined rules do nothing, so the
+    /// returned rules can always be evaluated.
+    pub fn rules_recovering(&self) -> (crate::RuleSet, Vec<Error>) {
         let mut lexer = self.lexer.clone();
-        build_rules(&mut lexer).map_err(|kind| Error { lexer, kind })
+        let mut diagnostics = vec![];
+        let rules = build_rules(&mut lexer, Some(&mut diagnostics))
+            .expect("errors are recorded as diagnostics");
+        let errors = diagnostics
+            .into_iter()
+            .map(|(kind, span)| self.error(kind, span))
+            .collect();
+        (rules, errors)
+    }
+
+    fn error(&self, kind: ErrorKind, span: std::ops::Range<usize>) -> Error {
+        let span = match self.source_map {
+            Some(source_map) => source_map.original_span(span),
+            None => span,
+        };
+        Error::new(kind, span, self.original)
     }
 }
 
@@ -83,8 +256,84 @@ fn next(lexer: &mut crate::Lexer) -> Result<Token, ErrorKind> {
     Err(ErrorKind::UnexpectedEOF)
 }
 
-fn parse_action_list(token: Token, lexer: &mut crate::Lexer) -> Result<crate::Action, ErrorKind> {
-    fn parse_transform(lexer: &mut crate::Lexer) -> Result<crate::Transform, ErrorKind> {
+fn get_color(token: Token, slice: &str) -> Result<crate::Color, ErrorKind> {
+    match token {
+        Token::LiteralHexColor | Token::RuleInvocation => {
+            crate::Color::parse(slice).ok_or(ErrorKind::InvalidColor)
+        }
+        _ => Err(ErrorKind::InvalidColor),
+    }
+}
+
+fn parse_color(lexer: &mut crate::Lexer) -> Result<crate::Color, ErrorKind> {
+    next(lexer).and_then(|token| get_color(token, lexer.slice()))
+}
+
+/// Consumes the next whitespace delimited word, which may contain characters the lexer rejects.
+fn next_word<'source>(lexer: &mut crate::Lexer<'source>) -> Result<&'source str, ErrorKind> {
+    let remainder = lexer.remainder();
+    let start = remainder.len() - remainder.trim_start_matches([' ', '\t']).len();
+    let len = remainder[start..]
+        .find(char::is_whitespace)
+        .unwrap_or(remainder.len() - start);
+    if len == 0 {
+        return Err(ErrorKind::UnexpectedEOF);
+    }
+    lexer.bump(start + len);
+    Ok(&remainder[start..start + len])
+}
+
+/// Consumes a bracketed list of numbers separated by spaces or commas, e.g. `[0.5, 1 -2]`.
+fn next_vector(lexer: &mut crate::Lexer) -> Result<Vec<f32>, ErrorKind> {
+    let remainder = lexer.remainder();
+    let start = remainder.len() - remainder.trim_start_matches([' ', '\t']).len();
+    let len = match remainder[start..].strip_prefix('[') {
+        Some(rest) => rest.find(']').ok_or(ErrorKind::ExpectedVector)? + 2,
+        None => return Err(ErrorKind::ExpectedVector),
+    };
+    lexer.bump(start + len);
+    remainder[start + 1..start + len - 1]
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|number| !number.is_empty())
+        .map(|number| number.parse().map_err(|_| ErrorKind::ExpectedVector))
+        .collect()
+}
+
+fn parse_triangle(slice: &str) -> Result<[[f32; 3]; 3], ErrorKind> {
+    fn triple<T>(
+        mut items: impl Iterator<Item = Result<T, ErrorKind>>,
+    ) -> Result<[T; 3], ErrorKind> {
+        let mut next = || items.next().unwrap_or(Err(ErrorKind::InvalidTriangle));
+        let triple = [next()?, next()?, next()?];
+        match items.next() {
+            None => Ok(triple),
+            Some(_) => Err(ErrorKind::InvalidTriangle),
+        }
+    }
+
+    let vertices = slice
+        .strip_prefix("triangle[")
+        .and_then(|s| s.strip_suffix(']'))
+        .ok_or(ErrorKind::InvalidTriangle)?;
+    triple(vertices.split(';').map(|vertex| {
+        triple(vertex.split(',').map(|coordinate| {
+            coordinate
+                .trim()
+                .parse()
+                .map_err(|_| ErrorKind::InvalidTriangle)
+        }))
+    }))
+}
+
+fn parse_action_list(
+    token: Token,
+    lexer: &mut crate::Lexer,
+    rules: &mut crate::RuleSet,
+) -> Result<crate::Action, ErrorKind> {
+    /// Parses the transforms of one loop, starting a new one at each blend so that blends wrap
+    /// the hue they're applied to rather than the turns written before them.
+    fn parse_transform(lexer: &mut crate::Lexer) -> Result<Vec<crate::Transform>, ErrorKind> {
+        let mut transforms = vec![];
         let mut tx = crate::Transform::default();
 
         fn get_number(token: Token, slice: &str) -> Result<f32, ErrorKind> {
@@ -101,7 +350,10 @@ fn parse_action_list(token: Token, lexer: &mut crate::Lexer) -> Result<crate::Ac
 
         while let Some(token) = crate::Lexer::next(lexer) {
             match token {
-                Token::BracketClose => return Ok(tx),
+                Token::BracketClose => {
+                    transforms.push(tx);
+                    return Ok(transforms);
+                }
                 Token::X => tx *= crate::Transform::translation(next_number(lexer)?, 0., 0.),
                 Token::Y => tx *= crate::Transform::translation(0., next_number(lexer)?, 0.),
                 Token::Z => tx *= crate::Transform::translation(0., 0., next_number(lexer)?),
@@ -120,22 +372,45 @@ fn parse_action_list(token: Token, lexer: &mut crate::Lexer) -> Result<crate::Ac
                     };
                     tx *= crate::Transform::scale(x, y, z);
                 }
-                Token::Hue => {
-                    tx.hue = next_number(lexer)?;
+                Token::Matrix | Token::RuleInvocation
+                    if matches!(lexer.slice(), "matrix" | "m") =>
+                {
+                    let mut m = [0.; 9];
+                    for v in &mut m {
+                        *v = next_number(lexer)?;
+                    }
+                    tx *= crate::Transform::matrix(m);
                 }
-                Token::Sat => {
-                    tx.sat = next_number(lexer)?;
+                Token::Fx => tx *= crate::Transform::reflect_x(),
+                Token::Fy => tx *= crate::Transform::reflect_y(),
+                Token::Fz => tx *= crate::Transform::reflect_z(),
+                Token::Hue => tx *= crate::Transform::hsv(next_number(lexer)?, 1., 1.),
+                Token::Sat => tx *= crate::Transform::hsv(0., next_number(lexer)?, 1.),
+                Token::Brightness => tx *= crate::Transform::hsv(0., 1., next_number(lexer)?),
+                Token::Alpha => {
+                    tx.alpha *= next_number(lexer)?;
                 }
-                Token::Brightness => {
-                    tx.brightness = next_number(lexer)?;
+                Token::Color => {
+                    let token = next(lexer)?;
+                    tx *= match (token, lexer.slice()) {
+                        (Token::RuleInvocation, "random") => crate::Transform::random_color(),
+                        (token, slice) => crate::Transform::color(get_color(token, slice)?),
+                    };
                 }
-                Token::Alpha => {
-                    tx.alpha = next_number(lexer)?;
+                Token::Blend => {
+                    let color = parse_color(lexer)?;
+                    let strength = next_number(lexer)?;
+                    if !(strength >= 0. && strength.is_finite()) {
+                        return Err(ErrorKind::InvalidBlendStrength);
+                    }
+                    transforms.push(tx);
+                    tx = crate::Transform::blend(color, strength);
                 }
                 _ => return Err(ErrorKind::UnexpectedTransformToken),
             }
         }
-        Ok(tx)
+        transforms.push(tx);
+        Ok(transforms)
     }
 
     fn starts_action(token: Token) -> bool {
@@ -146,142 +421,362 @@ fn parse_action_list(token: Token, lexer: &mut crate::Lexer) -> Result<crate::Ac
     let mut loops = vec![];
     while starts_action(token) {
         let count = match token {
-            Token::BracketOpen => 1,
             Token::LiteralInteger => {
                 let count = std::str::FromStr::from_str(lexer.slice())?;
-
-                assert_eq!(lexer.next(), Some(Token::Multiply));
-                assert_eq!(lexer.next(), Some(Token::BracketOpen));
+                if next(lexer)? != Token::Multiply {
+                    return Err(ErrorKind::ExpectedMultiply);
+                }
+                if next(lexer)? != Token::BracketOpen {
+                    return Err(ErrorKind::ExpectedOpeningBrace);
+                }
                 count
             }
-            _ => panic!(),
+            _ => 1,
         };
-        let transform = parse_transform(lexer)?;
-        let tx_loop = crate::TransformationLoop { count, transform };
+        let transforms = parse_transform(lexer)?;
+        let tx_loop = crate::TransformationLoop { count, transforms };
         loops.push(tx_loop);
         token = next(lexer)?;
     }
-    match token {
-        Token::RuleInvocation => Ok(crate::Action::Transform(crate::TransformAction {
-            loops,
-            rule: lexer.slice().to_string(),
-        })),
-        _ => Err(ErrorKind::ExpectedIdentifier),
+    let rule = lexer.slice();
+    let inline = match token {
+        Token::RuleInvocation => None,
+        Token::Triangle => Some((
+            crate::Primitive::Triangle,
+            crate::Geometry::Triangle(parse_triangle(rule)?),
+        )),
+        Token::TemplateMarker => {
+            let name = rule.trim_start_matches("template::").to_string();
+            Some((crate::Primitive::Template, crate::Geometry::Template(name)))
+        }
+        _ => return Err(ErrorKind::ExpectedIdentifier),
+    };
+    if let Some((primitive, geometry)) = inline {
+        rules.add_inline_primitive(rule, primitive, geometry);
     }
+    Ok(crate::Action::Transform(crate::TransformAction {
+        loops,
+        rule: rule.to_string(),
+    }))
 }
 
-fn build_rules(lexer: &mut crate::Lexer) -> Result<crate::RuleSet, ErrorKind> {
-    let mut is_comment = false;
-    let mut rules = crate::RuleSet::new();
+/// Extends the current identifier with any `::key` segments, as in `raytracer::max-depth`.
+fn namespaced_key<'source>(lexer: &mut crate::Lexer<'source>) -> Option<&'source str> {
+    let remainder = lexer.remainder();
+    if !remainder.starts_with("::") {
+        return None;
+    }
+    let len = remainder
+        .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | ':')))
+        .unwrap_or(remainder.len());
+    lexer.bump(len);
+    Some(lexer.slice())
+}
 
-    while let Some(token) = crate::Lexer::next(lexer) {
-        if is_comment && !matches!(token, Token::MultilineCommentEnd) {
-            continue;
+fn parse_setting(lexer: &mut crate::Lexer) -> Result<crate::Setting, ErrorKind> {
+    if lexer.remainder().trim_start().starts_with('[') {
+        return Ok(crate::Setting::Vector(next_vector(lexer)?));
+    }
+    match (self::next(lexer)?, lexer.slice()) {
+        (Token::LiteralInteger | Token::LiteralFloat, number) => {
+            Ok(crate::Setting::Number(number.parse()?))
         }
+        (Token::RuleInvocation, "true") => Ok(crate::Setting::Bool(true)),
+        (Token::RuleInvocation, "false") => Ok(crate::Setting::Bool(false)),
+        _ => Err(ErrorKind::ExpectedSettingValue),
+    }
+}
 
-        match token {
-            Token::MultilineCommentStart => {
-                is_comment = true;
+fn parse_set(lexer: &mut crate::Lexer) -> Result<crate::SetAction, ErrorKind> {
+    let set_type = crate::Lexer::next(lexer).ok_or(ErrorKind::UnexpectedEOF)?;
+    fn integer(lexer: &mut crate::Lexer) -> Result<usize, ErrorKind> {
+        let setting = crate::Lexer::next(lexer).ok_or(ErrorKind::UnexpectedEOF)?;
+        if let Token::LiteralInteger = setting {
+            Ok(lexer.slice().parse()?)
+        } else {
+            Err(ErrorKind::ExpectedNumber)
+        }
+    }
+
+    fn float(lexer: &mut crate::Lexer) -> Result<f32, ErrorKind> {
+        let setting = crate::Lexer::next(lexer).ok_or(ErrorKind::UnexpectedEOF)?;
+        if let Token::LiteralInteger | Token::LiteralFloat = setting {
+            Ok(lexer.slice().parse()?)
+        } else {
+            Err(ErrorKind::ExpectedNumber)
+        }
+    }
+
+    match set_type {
+        Token::MaxDepth => Ok(crate::SetAction::MaxDepth(integer(lexer)?)),
+        Token::MaxObjects => Ok(crate::SetAction::MaxObjects(integer(lexer)?)),
+        Token::Recursion => {
+            let setting = self::next(lexer)?;
+            match (setting, lexer.slice()) {
+                (Token::RuleInvocation, "depth") => {
+                    Ok(crate::SetAction::Recursion(crate::Recursion::DepthFirst))
+                }
+                (Token::RuleInvocation, "breadth") => {
+                    Ok(crate::SetAction::Recursion(crate::Recursion::BreadthFirst))
+                }
+                _ => Err(ErrorKind::ExpectedIdentifier),
             }
-            Token::MultilineCommentEnd => {
-                is_comment = false;
+        }
+        Token::MinSize => Ok(crate::SetAction::MinSize(float(lexer)?)),
+        Token::MaxSize => Ok(crate::SetAction::MaxSize(float(lexer)?)),
+        Token::ColorPool => crate::ColorPool::parse(next_word(lexer)?)
+            .map(crate::SetAction::ColorPool)
+            .ok_or(ErrorKind::InvalidColorPool),
+        Token::RuleInvocation => {
+            fn vector<const N: usize>(lexer: &mut crate::Lexer) -> Result<[f32; N], ErrorKind> {
+                next_vector(lexer)?
+                    .try_into()
+                    .map_err(|_| ErrorKind::ExpectedVector)
             }
-            Token::RuleDefinition => {
-                let name = lexer.slice().trim_start_matches("rule ").to_string();
-                let mut rule = crate::RuleDefinition {
-                    name,
-                    max_depth: None,
-                    retirement_rule: None,
-                    weight: 1.0,
-                };
 
-                while let Ok(token) = self::next(lexer) {
-                    match token {
-                        Token::BracketOpen => break,
-                        Token::MaxDepth => {
-                            fn ret(lexer: &mut crate::Lexer) -> Result<String, ErrorKind> {
-                                let retirement_rule = self::next(lexer)?;
-                                if matches!(retirement_rule, Token::RuleInvocation) {
-                                    Ok(lexer.slice().to_string())
-                                } else {
-                                    Err(ErrorKind::ExpectedIdentifier)
-                                }
-                            }
+            if let Some(key) = namespaced_key(lexer) {
+                return Ok(crate::SetAction::Namespaced(
+                    key.to_string(),
+                    parse_setting(lexer)?,
+                ));
+            }
 
-                            rule.max_depth = Some({
-                                self::next(lexer)?;
-                                lexer.slice().parse()?
-                            });
-                            let mut temp = lexer.clone();
-                            rule.retirement_rule =
-                                if let Ok(Token::MoreThan) = self::next(&mut temp) {
-                                    std::mem::swap(lexer, &mut temp);
-                                    Some(ret(lexer)?)
-                                } else {
-                                    None
-                                };
-                        }
-                        Token::Weight => {
-                            rule.weight = {
-                                self::next(lexer)?;
-                                std::str::FromStr::from_str(lexer.slice())?
-                            };
+            match lexer.slice() {
+                "background" => Ok(crate::SetAction::Background(parse_color(lexer)?)),
+                "translation" => Ok(crate::SetAction::Translation(vector(lexer)?)),
+                "rotation" => Ok(crate::SetAction::Rotation(vector(lexer)?)),
+                "pivot" => Ok(crate::SetAction::Pivot(vector(lexer)?)),
+                "scale" => Ok(crate::SetAction::Scale(float(lexer)?)),
+                "seed" => {
+                    let mut temp = lexer.clone();
+                    match (self::next(&mut temp)?, temp.slice()) {
+                        (Token::RuleInvocation, "initial") => {
+                            *lexer = temp;
+                            Ok(crate::SetAction::ResetSeed)
                         }
-                        Token::Error => {}
-                        _ => return Err(ErrorKind::UnexpectedRuleDefinitionToken),
+                        _ => Ok(crate::SetAction::Seed(integer(lexer)? as u64)),
                     }
                 }
+                "syncrandom" => match (self::next(lexer)?, lexer.slice()) {
+                    (Token::RuleInvocation, "true") => Ok(crate::SetAction::SyncRandom(true)),
+                    (Token::RuleInvocation, "false") => Ok(crate::SetAction::SyncRandom(false)),
+                    _ => Err(ErrorKind::ExpectedIdentifier),
+                },
+                _ => Err(ErrorKind::ExpectedIdentifier),
+            }
+        }
+        _ => Err(ErrorKind::ExpectedIdentifier),
+    }
+}
 
-                fn starts_action(token: Token) -> bool {
-                    matches!(
-                        token,
-                        Token::BracketOpen | Token::LiteralInteger | Token::RuleInvocation
-                    )
-                }
+/// Remembers where each rule is first invoked so that undefined rules can be reported there.
+fn reference<'source>(
+    references: &mut Vec<(String, crate::Lexer<'source>)>,
+    lexer: &crate::Lexer<'source>,
+) {
+    let name = lexer.slice();
+    if !references.iter().any(|(rule, _)| rule == name) {
+        references.push((name.to_string(), lexer.clone()));
+    }
+}
 
-                let mut next = self::next(lexer)?;
-                let mut actions = vec![];
-                while starts_action(next) {
-                    let action = parse_action_list(next, lexer)?;
-                    actions.push(action);
-                    next = self::next(lexer)?;
-                }
-                assert_eq!(Token::BracketClose, next, "{:?}", lexer.span());
-                rules.push(super::Rule::Custom(super::Custom { rule, actions }));
-            }
-            Token::Set => {
-                let set_type = crate::Lexer::next(lexer).ok_or(ErrorKind::UnexpectedEOF)?;
-                let set_action = match set_type {
+fn parse_item<'source>(
+    token: Token,
+    lexer: &mut crate::Lexer<'source>,
+    rules: &mut crate::RuleSet,
+    references: &mut Vec<(String, crate::Lexer<'source>)>,
+) -> Result<(), ErrorKind> {
+    match token {
+        Token::RuleDefinition => {
+            let definition = lexer.clone();
+            let name = lexer.slice().trim_start_matches("rule ").to_string();
+            let mut rule = crate::RuleDefinition {
+                name,
+                max_depth: None,
+                retirement_rule: None,
+                weight: 1.0,
+            };
+
+            while let Ok(token) = self::next(lexer) {
+                match token {
+                    Token::BracketOpen => break,
                     Token::MaxDepth => {
-                        let setting = crate::Lexer::next(lexer).ok_or(ErrorKind::UnexpectedEOF)?;
-                        if let Token::LiteralInteger = setting {
-                            let max_depth = lexer.slice().parse()?;
-                            Ok(crate::SetAction::MaxDepth(max_depth))
+                        fn ret(lexer: &mut crate::Lexer) -> Result<String, ErrorKind> {
+                            let retirement_rule = self::next(lexer)?;
+                            if matches!(retirement_rule, Token::RuleInvocation) {
+                                Ok(lexer.slice().to_string())
+                            } else {
+                                Err(ErrorKind::ExpectedIdentifier)
+                            }
+                        }
+
+                        rule.max_depth = Some({
+                            self::next(lexer)?;
+                            lexer.slice().parse()?
+                        });
+                        let mut temp = lexer.clone();
+                        rule.retirement_rule = if let Ok(Token::MoreThan) = self::next(&mut temp) {
+                            std::mem::swap(lexer, &mut temp);
+                            let retirement_rule = ret(lexer)?;
+                            reference(references, lexer);
+                            Some(retirement_rule)
                         } else {
-                            Err(ErrorKind::ExpectedNumber)
+                            None
+                        };
+                    }
+                    Token::Weight => {
+                        rule.weight = {
+                            self::next(lexer)?;
+                            std::str::FromStr::from_str(lexer.slice())?
+                        };
+                        if !rule.weight.is_finite() {
+                            return Err(ErrorKind::InvalidWeight);
                         }
                     }
-                    _ => Err(ErrorKind::ExpectedIdentifier),
-                }?;
-                rules.add_action(crate::Action::Set(set_action));
+                    Token::Error => {}
+                    _ => return Err(ErrorKind::UnexpectedRuleDefinitionToken),
+                }
             }
-            Token::RuleInvocation => {
-                let rule = lexer.slice().to_string();
-                rules.add_action(crate::Action::Transform(crate::TransformAction {
-                    loops: vec![],
-                    rule,
-                }))
+
+            fn starts_action(token: Token) -> bool {
+                matches!(
+                    token,
+                    Token::BracketOpen
+                        | Token::LiteralInteger
+                        | Token::RuleInvocation
+                        | Token::Triangle
+                        | Token::TemplateMarker
+                )
             }
-            Token::LiteralInteger => {
-                rules.add_action(parse_action_list(token, lexer)?);
+
+            let mut next = self::next(lexer)?;
+            let mut actions = vec![];
+            loop {
+                let action = match next {
+                    Token::Set => crate::Action::Set(parse_set(lexer)?),
+                    token if starts_action(token) => {
+                        let action = parse_action_list(token, lexer, rules)?;
+                        reference(references, lexer);
+                        action
+                    }
+                    _ => break,
+                };
+                actions.push(action);
+                next = self::next(lexer)?;
             }
-            Token::BracketOpen => {
-                rules.add_action(parse_action_list(token, lexer)?);
+            if next != Token::BracketClose {
+                return Err(ErrorKind::ExpectedClosingBrace);
             }
-            Token::Error => {}
+            if let Err(kind) = rules.push(super::Custom { rule, actions }) {
+                *lexer = definition;
+                return Err(kind);
+            }
+        }
+        Token::Set => {
+            let set_action = parse_set(lexer)?;
+            rules.set(set_action);
+        }
+        Token::RuleInvocation => {
+            let rule = lexer.slice().to_string();
+            reference(references, lexer);
+            rules.add_action(crate::Action::Transform(crate::TransformAction {
+                loops: vec![],
+                rule,
+            }))
+        }
+        Token::LiteralInteger | Token::BracketOpen | Token::Triangle | Token::TemplateMarker => {
+            let action = parse_action_list(token, lexer, rules)?;
+            reference(references, lexer);
+            rules.add_action(action);
+        }
+        Token::Error => {}
+        _ => return Err(ErrorKind::UnexpectedTopLevelToken),
+    }
+    Ok(())
+}
+
+/// Skips past the item that failed to parse: to the `}` closing whatever was open when it
+/// started, or up to the next `rule` or top level `set`.
+fn synchronize(start: &crate::Lexer, lexer: &mut crate::Lexer) {
+    let end = lexer.span().end;
+    let mut depth = 0;
+    let mut counter = start.clone();
+    while let Some(token) = counter.next() {
+        if counter.span().end > end {
+            break;
+        }
+        match token {
+            Token::BracketOpen => depth += 1,
+            Token::BracketClose => depth -= 1,
+            _ => {}
+        }
+    }
+
+    loop {
+        let mut peek = lexer.clone();
+        match peek.next() {
+            None | Some(Token::RuleDefinition) => return,
+            Some(Token::Set) if depth <= 0 => return,
+            Some(Token::BracketOpen) => depth += 1,
+            Some(Token::BracketClose) => {
+                depth -= 1;
+                if depth <= 0 {
+                    *lexer = peek;
+                    return;
+                }
+            }
+            _ => {}
+        }
+        *lexer = peek;
+    }
+}
+
+/// Parses a whole script. Without `diagnostics` the first error is returned; otherwise each
+/// error is recorded along with its span and parsing resumes after the offending item.
+fn build_rules(
+    lexer: &mut crate::Lexer,
+    mut diagnostics: Option<&mut Vec<(ErrorKind, std::ops::Range<usize>)>>,
+) -> Result<crate::RuleSet, ErrorKind> {
+    let mut is_comment = false;
+    let mut rules = crate::RuleSet::new();
+    let mut references = vec![];
+
+    loop {
+        let start = lexer.clone();
+        let token = match crate::Lexer::next(lexer) {
+            Some(token) => token,
+            None => break,
+        };
+        if is_comment && !matches!(token, Token::MultilineCommentEnd) {
+            continue;
+        }
+
+        match token {
+            Token::MultilineCommentStart => is_comment = true,
+            Token::MultilineCommentEnd => is_comment = false,
             token => {
-                eprintln!("{:?}", token);
-                return Err(ErrorKind::UnexpectedTopLevelToken);
+                if let Err(kind) = parse_item(token, lexer, &mut rules, &mut references) {
+                    match &mut diagnostics {
+                        Some(diagnostics) => diagnostics.push((kind, lexer.span())),
+                        None => return Err(kind),
+                    }
+                    synchronize(&start, lexer);
+                }
+            }
+        }
+    }
+
+    for (rule, invocation) in references {
+        if !rules.rules.contains_key(&rule) {
+            match &mut diagnostics {
+                Some(diagnostics) => {
+                    diagnostics.push((ErrorKind::UndefinedRule, invocation.span()));
+                    rules.define_empty(&rule);
+                }
+                None => {
+                    *lexer = invocation;
+                    return Err(ErrorKind::UndefinedRule);
+                }
             }
         }
     }
@@ -307,16 +802,162 @@ mod tests {
             rules
                 .rules
                 .values()
-                .filter(|rule| match rule {
-                    crate::Rule::Primitive(_) => false,
-                    _ => true,
-                })
+                .filter(|rule| !matches!(rule, crate::Rule::Primitive(..)))
                 .count(),
             1
         );
     }
 
-    const INPUT: &'static str = r#"/*
+    #[test]
+    fn primitive_redefinition() {
+        let source = "box\nrule box { sphere }";
+        let err = Parser::new(crate::Lexer::new(source)).rules().unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::PrimitiveRedefinition));
+        assert_eq!(&source[err.span], "rule box");
+    }
+
+    #[test]
+    fn error_location() {
+        let source = "set maxdepth 10\n\n{ x 1 }\tr1\nrule r1 {\n\t{ x 1 s q } box\n}";
+        let err = Parser::new(crate::Lexer::new(source)).rules().unwrap_err();
+        assert_eq!((err.line, err.column), (5, 10));
+        assert_eq!(err.source_line, "\t{ x 1 s q } box");
+        assert_eq!(err.found, "q");
+        assert_eq!(
+            err.to_string(),
+            "Expected a number.
+ --> 5:10
+  |
+5 | \t{ x 1 s q } box
+  | \t        ^ expected a number, found `q`"
+        );
+
+        let err = Parser::new(crate::Lexer::new("{ x 1 } r1 rule r1 {"))
+            .rules()
+            .unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::UnexpectedEOF));
+        assert_eq!((err.line, err.column), (1, 21));
+        assert!(err.to_string().ends_with("found the end of the script"));
+    }
+
+    #[test]
+    fn malformed_input() {
+        let cases = [
+            ("3 { x 1 } box", ErrorKind::ExpectedMultiply, "{"),
+            ("3 * x 1 } box", ErrorKind::ExpectedOpeningBrace, "x"),
+            (
+                "rule r { box set maxdepth 2 ] ",
+                ErrorKind::UnexpectedEOF,
+                "",
+            ),
+            ("rule r { box > }", ErrorKind::ExpectedClosingBrace, ">"),
+            (
+                "{ x 1 } r1 r2 rule r1 { box }",
+                ErrorKind::UndefinedRule,
+                "r2",
+            ),
+            (
+                "rule r md 2 > gone { box } r",
+                ErrorKind::UndefinedRule,
+                "gone",
+            ),
+            (
+                "rule r w 0 { box } rule r w 0 { dot }",
+                ErrorKind::InvalidWeight,
+                "rule r",
+            ),
+            (
+                "rule r w -1 { box } rule r { dot }",
+                ErrorKind::InvalidWeight,
+                "rule r",
+            ),
+            (
+                "rule r w 999999999999999999999999999999999999999999 { box } rule r { dot }",
+                ErrorKind::InvalidWeight,
+                "999999999999999999999999999999999999999999",
+            ),
+            (
+                "rule r w inf { box } rule r { dot }",
+                ErrorKind::InvalidWeight,
+                "inf",
+            ),
+            (
+                "rule r w 300000000000000000000000000000000000000 { box }
+                rule r w 300000000000000000000000000000000000000 { dot }",
+                ErrorKind::InvalidWeight,
+                "rule r",
+            ),
+        ];
+        for (source, kind, found) in cases {
+            let err = Parser::new(crate::Lexer::new(source)).rules().unwrap_err();
+            assert_eq!(
+                (&err.kind, err.found.as_str()),
+                (&kind, found),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn recovering() {
+        let source = "{ x 1 } box
+3 { y 1 } box
+rule r1 { { x q } box }
+rule r2 { sphere > }
+rule box { dot }
+{ z 1 } r3 r2 r1
+rule r1 { dot }";
+        let parser = Parser::new(crate::Lexer::new(source));
+        let (rules, errors) = parser.rules_recovering();
+        let errors = errors
+            .iter()
+            .map(|err| (err.kind.clone(), err.line, err.found.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                (ErrorKind::ExpectedMultiply, 2, "{"),
+                (ErrorKind::ExpectedNumber, 3, "q"),
+                (ErrorKind::ExpectedClosingBrace, 4, ">"),
+                (ErrorKind::PrimitiveRedefinition, 5, "rule box"),
+                (ErrorKind::UndefinedRule, 6, "r3"),
+                (ErrorKind::UndefinedRule, 6, "r2"),
+            ]
+        );
+
+        use crate::{Primitive, Transform};
+        let mut rng = rand::thread_rng();
+        let mut ctx = crate::ContextMut::new(&mut rng);
+        let cmds = rules.iter(&mut ctx).collect::<Vec<_>>();
+        assert_eq!(
+            cmds,
+            vec![
+                (Transform::translation(1., 0., 0.), Primitive::Box),
+                (Transform::default(), Primitive::Box),
+                (Transform::default(), Primitive::Dot),
+            ]
+        );
+
+        let (_, errors) =
+            Parser::new(crate::Lexer::new(source.lines().next().unwrap())).rules_recovering();
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn error_outlives_source() {
+        fn parse(
+            source: String,
+        ) -> Result<crate::RuleSet, Box<dyn std::error::Error + Send + Sync>> {
+            Ok(Parser::new(crate::Lexer::new(&source)).rules()?)
+        }
+
+        let err = parse("{ x } box".to_string()).unwrap_err();
+        let err = std::thread::spawn(move || err.to_string()).join().unwrap();
+        assert!(err.starts_with("Expected a number."));
+    }
+
+    const INPUT: &str = r#"/*
   Sample Torus.
 */
 
@@ -0,0 +1,391 @@
+use std::ops::Range;
+
+/// A contiguous run of preprocessed output and the original text it came from.
+#[derive(Debug, Clone, PartialEq)]
+struct Segment {
+    output: Range<usize>,
+    original: Range<usize>,
+    expanded: bool,
+}
+
+/// Maps byte offsets in preprocessed text back to the original source.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceMap {
+    segments: Vec<Segment>,
+}
+
+impl SourceMap {
+    fn push(&mut self, output: Range<usize>, original: Range<usize>, expanded: bool) {
+        if output.is_empty() {
+            return;
+        }
+        if let Some(last) = self.segments.last_mut() {
+            let contiguous = last.output.end == output.start && last.original.end == original.start;
+            if contiguous && !last.expanded && !expanded {
+                last.output.end = output.end;
+                last.original.end = original.end;
+                return;
+            }
+        }
+        self.segments.push(Segment {
+            output,
+            original,
+            expanded,
+        });
+    }
+
+    fn segment(&self, offset: usize) -> Option<&Segment> {
+        let index = self
+            .segments
+            .partition_point(|segment| segment.output.end <= offset);
+        self.segments.get(index)
+    }
+
+    /// Translates a span of preprocessed text into a span of the original source.
+    ///
+    /// Spans that land inside an expanded define cover the whole name at the use site.
+    pub fn original_span(&self, span: Range<usize>) -> Range<usize> {
+        let start = match self.segment(span.start) {
+            Some(segment) if segment.expanded => segment.original.start,
+            Some(segment) => segment.original.start + (span.start - segment.output.start),
+            None => self.segments.last().map_or(span.start, |s| s.original.end),
+        };
+        let end = match self.segment(span.end.saturating_sub(1)) {
+            _ if span.end <= span.start => start,
+            Some(segment) if segment.expanded => segment.original.end,
+            Some(segment) => segment.original.start + (span.end - segment.output.start),
+            None => start,
+        };
+        start..end.max(start)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParameterKind {
+    Float,
+    Int,
+}
+
+/// A define annotated for interactive editing, e.g. `#define angle 20 (float:0-90)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Parameter {
+    pub name: String,
+    pub kind: ParameterKind,
+    pub default: f32,
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Parameter {
+    fn format(&self, value: f32) -> String {
+        match self.kind {
+            ParameterKind::Float => value.to_string(),
+            ParameterKind::Int => (value.round() as i64).to_string(),
+        }
+    }
+}
+
+/// Expands C-style `#define name value` directives ahead of lexing.
+///
+/// Defines apply to every line after the directive. Names are only substituted
+/// when they appear as whole identifiers, so `#define s 0.5` leaves `sphere` alone.
+#[derive(Debug, Clone)]
+pub struct Preprocessor<'source> {
+    source: &'source str,
+    overrides: std::collections::BTreeMap<String, f32>,
+}
+
+impl<'source> Preprocessor<'source> {
+    pub fn new(source: &'source str) -> Self {
+        Self {
+            source,
+            overrides: Default::default(),
+        }
+    }
+
+    /// Replaces the value of the define `name` wherever it is expanded.
+    pub fn set(&mut self, name: &str, value: f32) -> &mut Self {
+        self.overrides.insert(name.to_string(), value);
+        self
+    }
+
+    pub fn process(&self) -> Preprocessed<'source> {
+        let mut defines = std::collections::BTreeMap::new();
+        let mut parameters = vec![];
+        let mut output = String::with_capacity(self.source.len());
+        let mut source_map = SourceMap::default();
+
+        let mut offset = 0;
+        for line in self.source.split_inclusive('\n') {
+            let content = line.trim_end_matches(&['\r', '\n'][..]);
+            let directive = content.trim_start();
+
+            if directive.starts_with('#') {
+                if let Some((name, value)) = parse_define(directive) {
+                    let value = match parse_parameter(name, value) {
+                        Some(parameter) => {
+                            let value = self.overrides.get(name).copied();
+                            let value = parameter.format(value.unwrap_or(parameter.default));
+                            parameters.push(parameter);
+                            value
+                        }
+                        None => match self.overrides.get(name) {
+                            Some(value) => value.to_string(),
+                            None => value.to_string(),
+                        },
+                    };
+                    defines.insert(name.to_string(), value);
+                }
+                let newline = offset + content.len()..offset + line.len();
+                let start = output.len();
+                output.push_str(&line[content.len()..]);
+                source_map.push(start..output.len(), newline, false);
+            } else {
+                expand_line(line, offset, &defines, &mut output, &mut source_map);
+            }
+            offset += line.len();
+        }
+
+        Preprocessed {
+            original: self.source,
+            source: output,
+            source_map,
+            parameters,
+        }
+    }
+}
+
+fn parse_parameter(name: &str, value: &str) -> Option<Parameter> {
+    let (default, annotation) = value.strip_suffix(')')?.rsplit_once('(')?;
+    let (kind, range) = annotation.split_once(':')?;
+    let kind = match kind.trim() {
+        "float" => ParameterKind::Float,
+        "int" => ParameterKind::Int,
+        _ => return None,
+    };
+    let range = range.trim();
+    let separator = range.get(1..)?.find('-')? + 1;
+    let (min, max) = (&range[..separator], &range[separator + 1..]);
+
+    Some(Parameter {
+        name: name.to_string(),
+        kind,
+        default: default.trim().parse().ok()?,
+        min: min.trim().parse().ok()?,
+        max: max.trim().parse().ok()?,
+    })
+}
+
+fn parse_define(directive: &str) -> Option<(&str, &str)> {
+    let rest = directive.strip_prefix("#define")?;
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let rest = rest.trim_start();
+    let name_len = rest
+        .find(|c: char| !is_identifier_char(c))
+        .unwrap_or(rest.len());
+    let (name, value) = rest.split_at(name_len);
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    Some((name, value.trim()))
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn expand_line(
+    line: &str,
+    offset: usize,
+    defines: &std::collections::BTreeMap<String, String>,
+    output: &mut String,
+    source_map: &mut SourceMap,
+) {
+    let mut copy = |output: &mut String, range: Range<usize>, value: Option<&str>| {
+        let start = output.len();
+        output.push_str(value.unwrap_or(&line[range.clone()]));
+        source_map.push(
+            start..output.len(),
+            offset + range.start..offset + range.end,
+            value.is_some(),
+        );
+    };
+
+    let bytes = line.as_bytes();
+    let mut verbatim_start = 0;
+    let mut index = 0;
+    while index < bytes.len() {
+        let c = bytes[index] as char;
+        if !is_identifier_char(c) {
+            index += 1;
+            continue;
+        }
+
+        let word_start = index;
+        while index < bytes.len() && is_identifier_char(bytes[index] as char) {
+            index += 1;
+        }
+        let is_name = !c.is_ascii_digit() && (word_start == 0 || bytes[word_start - 1] != b'#');
+        let value = if is_name {
+            defines.get(&line[word_start..index])
+        } else {
+            None
+        };
+
+        if let Some(value) = value {
+            copy(output, verbatim_start..word_start, None);
+            copy(output, word_start..index, Some(value));
+            verbatim_start = index;
+        }
+    }
+    copy(output, verbatim_start..line.len(), None);
+}
+
+/// The output of a [`Preprocessor`] along with the mapping back to the original text.
+#[derive(Debug, Clone)]
+pub struct Preprocessed<'source> {
+    original: &'source str,
+    source: String,
+    source_map: SourceMap,
+    parameters: Vec<Parameter>,
+}
+
+impl<'source> Preprocessed<'source> {
+    /// The unprocessed script.
+    pub fn original(&self) -> &'source str {
+        self.original
+    }
+
+    /// The script with every define expanded.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn source_map(&self) -> &SourceMap {
+        &self.source_map
+    }
+
+    /// The defines carrying a `(float:min-max)` or `(int:min-max)` annotation, in script order.
+    pub fn parameters(&self) -> &[Parameter] {
+        &self.parameters
+    }
+
+    /// A parser over the expanded script whose errors report spans in the original.
+    pub fn parser(&self) -> crate::Parser<'_> {
+        crate::Parser::with_source_map(
+            crate::Lexer::new(&self.source),
+            &self.source_map,
+            self.original,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_defines() {
+        let pre = Preprocessor::new("#define angle 6\n{ rz angle } box\n").process();
+        assert_eq!(pre.source(), "\n{ rz 6 } box\n");
+    }
+
+    #[test]
+    fn multi_token_define() {
+        let pre = Preprocessor::new("#define shrink s 0.996\n{ x 1 shrink } box").process();
+        assert_eq!(pre.source(), "\n{ x 1 s 0.996 } box");
+    }
+
+    #[test]
+    fn whole_identifiers_only() {
+        let pre =
+            Preprocessor::new("#define s 0.5\n#define _f3 10\n{ x s z -_f3 } sphere").process();
+        assert_eq!(pre.source(), "\n\n{ x 0.5 z -10 } sphere");
+    }
+
+    #[test]
+    fn defines_apply_after_directive() {
+        let pre = Preprocessor::new("{ x n } box\n#define n 2\n{ x n } box").process();
+        assert_eq!(pre.source(), "{ x n } box\n\n{ x 2 } box");
+    }
+
+    #[test]
+    fn gui_parameters() {
+        let source = "#define sizeStep 0.94 (float:0-1)\n#define iterations 6 (int:1-90)\n#define offset 2 (float:-10-10)\niterations * { s sizeStep x offset } box";
+        let pre = Preprocessor::new(source).process();
+        assert_eq!(pre.source(), "\n\n\n6 * { s 0.94 x 2 } box");
+        assert_eq!(
+            pre.parameters(),
+            &[
+                Parameter {
+                    name: "sizeStep".to_string(),
+                    kind: ParameterKind::Float,
+                    default: 0.94,
+                    min: 0.,
+                    max: 1.,
+                },
+                Parameter {
+                    name: "iterations".to_string(),
+                    kind: ParameterKind::Int,
+                    default: 6.,
+                    min: 1.,
+                    max: 90.,
+                },
+                Parameter {
+                    name: "offset".to_string(),
+                    kind: ParameterKind::Float,
+                    default: 2.,
+                    min: -10.,
+                    max: 10.,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn overridden_parameters() {
+        let source = "#define sizeStep 0.94 (float:0-1)\n#define iterations 6 (int:1-90)\niterations * { s sizeStep } box";
+        let pre = Preprocessor::new(source)
+            .set("sizeStep", 0.5)
+            .set("iterations", 3.4)
+            .process();
+        assert_eq!(pre.source(), "\n\n3 * { s 0.5 } box");
+        assert_eq!(pre.parameters()[1].default, 6.);
+
+        let rules = pre.parser().rules().unwrap();
+        let mut rng = rand::thread_rng();
+        let mut ctx = crate::ContextMut::new(&mut rng);
+        assert_eq!(rules.iter(&mut ctx).count(), 3);
+    }
+
+    #[test]
+    fn spans_map_to_original() {
+        let original = "#define angle 6\n{ rz angle x 1 } box";
+        let pre = Preprocessor::new(original).process();
+        let map = pre.source_map();
+
+        let expanded = pre.source().find('6').unwrap();
+        assert_eq!(
+            &original[map.original_span(expanded..expanded + 1)],
+            "angle"
+        );
+
+        let x = pre.source().find('x').unwrap();
+        assert_eq!(&original[map.original_span(x..x + 3)], "x 1");
+
+        let range = pre.source().find("rz").unwrap()..pre.source().find('x').unwrap() - 1;
+        assert_eq!(&original[map.original_span(range)], "rz angle");
+    }
+
+    #[test]
+    fn parse_errors_point_into_original() {
+        let original = "#define shrink s 0.996 q\n{ x 1 shrink } box";
+        let pre = Preprocessor::new(original).process();
+        let err = pre.parser().rules().unwrap_err();
+        assert_eq!(&original[err.span.clone()], "shrink");
+        assert_eq!((err.line, err.column), (2, 7));
+        assert_eq!(err.source_line, "{ x 1 shrink } box");
+    }
+}
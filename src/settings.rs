@@ -0,0 +1,65 @@
+/// The order in which pending rules are applied.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd)]
+pub enum Recursion {
+    /// Follow each branch to its end before starting its siblings.
+    DepthFirst,
+    /// Apply every rule of a generation before any of the next, as Structure Synth does by
+    /// default.
+    BreadthFirst,
+}
+
+/// The value of a namespaced setting such as `set raytracer::dof [0.2, 0.1]`.
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum Setting {
+    Number(f32),
+    Bool(bool),
+    Vector(Vec<f32>),
+}
+
+/// Global options for evaluating and displaying a [`RuleSet`](crate::RuleSet).
+///
+/// Scripts change these with `set` commands; they can also be adjusted from Rust after parsing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    /// The longest chain of rule applications before a branch is abandoned.
+    pub max_depth: usize,
+    /// The number of primitives after which evaluation stops, if any. Template markers don't
+    /// count, though markers that would come after the last primitive aren't emitted, so a
+    /// block begun before then may never be ended.
+    pub max_objects: Option<usize>,
+    pub recursion: Recursion,
+    /// Branches and primitives are culled once their
+    /// [`Transform::size`](crate::Transform::size) drops below this.
+    pub min_size: Option<f32>,
+    /// Branches and primitives are culled once their
+    /// [`Transform::size`](crate::Transform::size) grows beyond this.
+    pub max_size: Option<f32>,
+    /// Where `color random` draws its colors from.
+    pub color_pool: crate::ColorPool,
+    /// The color behind the structure, if the script chose one.
+    pub background: Option<crate::Color>,
+    /// The seed for random choices, otherwise drawn from the [`ContextMut`](crate::ContextMut).
+    pub seed: Option<u64>,
+    /// Whether each invocation restores the random stream from a seed drawn when it was
+    /// created, so that branches reached the same way make the same choices.
+    pub sync_random: bool,
+    /// Options for renderers and exporters, keyed by their full name like `raytracer::light`.
+    pub namespaced: std::collections::BTreeMap<String, Setting>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            max_depth: 1000,
+            max_objects: None,
+            recursion: Recursion::BreadthFirst,
+            min_size: None,
+            max_size: None,
+            color_pool: Default::default(),
+            background: None,
+            seed: None,
+            sync_random: false,
+            namespaced: Default::default(),
+        }
+    }
+}
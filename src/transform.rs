@@ -6,6 +6,16 @@ pub struct Transform {
     pub sat: f32,
     pub brightness: f32,
     pub alpha: f32,
+
+    /// How strongly the incoming hue, saturation and brightness carry through this transform.
+    ///
+    /// Each channel maps an incoming value `x` to `retained * x + offset`, where the offset is
+    /// what's left of the public field once the default color has been factored out. Relative
+    /// adjustments retain everything, absolute colors retain nothing and blends retain a
+    /// fraction, which lets all three compose.
+    retained: [f32; 3],
+    /// Whether the color that the adjustments above apply to is drawn from the color pool.
+    random_color: bool,
 }
 
 impl Transform {
@@ -56,11 +66,91 @@ impl Transform {
         }
     }
 
+    /// Applies a linear map, given in row-major order, about the center of the unit cube.
+    pub fn matrix(m: [f32; 9]) -> Transform {
+        let linear = nalgebra::Matrix3::from_row_slice(&m).to_homogeneous();
+        let tx = nalgebra::Matrix4::new_translation(&nalgebra::Vector3::new(0.5, 0.5, 0.5))
+            * linear
+            * nalgebra::Matrix4::new_translation(&nalgebra::Vector3::new(-0.5, -0.5, -0.5));
+        Self {
+            tx,
+            ..Default::default()
+        }
+    }
+
+    /// Mirrors across the plane `x = 0.5`.
+    pub fn reflect_x() -> Transform {
+        Self::scale(-1., 1., 1.)
+    }
+
+    /// Mirrors across the plane `y = 0.5`.
+    pub fn reflect_y() -> Transform {
+        Self::scale(1., -1., 1.)
+    }
+
+    /// Mirrors across the plane `z = 0.5`.
+    pub fn reflect_z() -> Transform {
+        Self::scale(1., 1., -1.)
+    }
+
+    /// The length of the unit cube's diagonal after transformation.
+    pub fn size(&self) -> f32 {
+        self.tx
+            .transform_vector(&nalgebra::Vector3::new(1., 1., 1.))
+            .norm()
+    }
+
+    /// Sets the color outright, discarding any hue, saturation or brightness before it.
+    pub fn color(color: crate::Color) -> Transform {
+        let [hue, sat, brightness] = color.hsv();
+        Self {
+            hue,
+            sat,
+            brightness,
+            retained: [0., 0., 0.],
+            ..Default::default()
+        }
+    }
+
+    /// Moves the color towards `color` in HSV space, weighting `color` by `strength` against
+    /// a weight of one for the current color. The current hue is wrapped into `0..360` first,
+    /// so a blend must be applied to a color rather than folded into another transform's turn.
+    pub fn blend(color: crate::Color, strength: f32) -> Transform {
+        let [hue, sat, brightness] = color.hsv();
+        let retained = 1. / (1. + strength);
+        let weight = strength / (1. + strength);
+        Self {
+            hue: weight * hue,
+            sat: retained + weight * sat,
+            brightness: retained + weight * brightness,
+            retained: [retained; 3],
+            ..Default::default()
+        }
+    }
+
+    /// Sets the color to one drawn from the color pool once the transform is applied.
+    pub(crate) fn random_color() -> Transform {
+        Self {
+            random_color: true,
+            ..Default::default()
+        }
+    }
+
+    /// Substitutes a concrete color for a pending `color random`.
+    pub(crate) fn resolve_random_color(mut self, color: impl FnOnce() -> crate::Color) -> Self {
+        if self.random_color {
+            self.random_color = false;
+            self = Self::color(color()) * self;
+        }
+        self
+    }
+
     pub fn hsv(hue: f32, sat: f32, brightness: f32) -> Transform {
         Self {
             hue,
             sat,
             brightness,
+            retained: [1., sat, brightness],
             ..Default::default()
         }
     }
@@ -70,9 +160,28 @@ impl std::ops::MulAssign for Transform {
     fn mul_assign(&mut self, rhs: Self) {
         self.tx *= rhs.tx;
 
-        self.hue += rhs.hue;
-        self.sat *= rhs.sat;
-        self.brightness *= rhs.brightness;
+        if rhs.random_color {
+            self.hue = rhs.hue;
+            self.sat = rhs.sat;
+            self.brightness = rhs.brightness;
+            self.retained = rhs.retained;
+            self.random_color = true;
+            self.alpha *= rhs.alpha;
+            return;
+        }
+
+        // the default color is a hue of 0 with full saturation and brightness
+        let [hue, sat, brightness] = rhs.retained;
+        if hue > 0. && hue < 1. {
+            // blends mix the hue as an angle, not as however far it has been turned
+            self.hue = self.hue.rem_euclid(360.);
+        }
+        self.hue = hue * self.hue + rhs.hue;
+        self.sat = sat * self.sat + (rhs.sat - sat);
+        self.brightness = brightness * self.brightness + (rhs.brightness - brightness);
+        for (retained, rhs) in self.retained.iter_mut().zip(rhs.retained) {
+            *retained *= rhs;
+        }
         self.alpha *= rhs.alpha;
     }
 }
@@ -94,6 +203,8 @@ impl Default for Transform {
             sat: 1.0,
             brightness: 1.0,
             alpha: 1.0,
+            retained: [1., 1., 1.],
+            random_color: false,
         }
     }
 }
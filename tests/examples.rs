@@ -0,0 +1,20 @@
+#[test]
+fn examples_parse() {
+    let root = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/Examples");
+    for name in [
+        "Tutorials/Preprocessor.es",
+        "Tutorials/PreprocessorGUI.es",
+        "Tutorials/NouveauMovie.es",
+        "Tutorials/RandomColor.es",
+        "Tutorials/CSG test.es",
+        "Tutorials/TriangleComposites.es",
+        "Tutorials/Blend.es",
+        "MeshTest.es",
+    ] {
+        let source = std::fs::read_to_string(root.join(name)).unwrap();
+        let preprocessed = eisenscript::Preprocessor::new(&source).process();
+        if let Err(err) = preprocessed.parser().rules() {
+            panic!("{} failed to parse: {}", name, err);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use rand::{Rng, SeedableRng};
+
+/// Fragments that are likely to confuse the parser when spliced into a script.
+const FRAGMENTS: &[&str] = &[
+    "{",
+    "}",
+    "*",
+    ">",
+    "[",
+    "]",
+    "3",
+    "-1",
+    "100000000000 *",
+    "0.5",
+    "rule",
+    "rule r",
+    "md",
+    "w",
+    "set",
+    "x",
+    "color",
+    "random",
+    "::",
+    "#",
+    "#define",
+    "triangle[",
+    "template::",
+    "\n",
+    " ",
+];
+
+fn mutate(source: &str, rng: &mut impl Rng) -> String {
+    let mut chars = source.chars().collect::<Vec<_>>();
+    for _ in 0..rng.gen_range(1..4) {
+        let at = rng.gen_range(0..=chars.len());
+        match rng.gen_range(0..3) {
+            0 if at < chars.len() => {
+                let end = (at + rng.gen_range(1..8)).min(chars.len());
+                chars.drain(at..end);
+            }
+            1 => {
+                let fragment = FRAGMENTS[rng.gen_range(0..FRAGMENTS.len())];
+                chars.splice(at..at, fragment.chars());
+            }
+            _ => chars.truncate(at),
+        }
+    }
+    chars.into_iter().collect()
+}
+
+/// Runs the script, bounded to a few objects and generations so that every mutation is quick.
+///
+/// Evaluation is depth first because breadth first has to apply every invocation of a huge loop
+/// before the next generation can emit anything.
+fn evaluate(mut rules: eisenscript::RuleSet) {
+    let settings = rules.settings_mut();
+    settings.recursion = eisenscript::Recursion::DepthFirst;
+    settings.max_objects = Some(100);
+    settings.max_depth = settings.max_depth.min(6);
+    let mut rng = rand::rngs::SmallRng::seed_from_u64(0);
+    rules
+        .iter(&mut eisenscript::ContextMut::new(&mut rng))
+        .for_each(drop);
+}
+
+/// Parses and evaluates `mutations` random mutations of every example script.
+fn fuzz(mutations: usize) {
+    let root = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/Examples");
+    let mut scripts = vec![];
+    for dir in [root.clone(), root.join("Tutorials")] {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "es") {
+                scripts.push(std::fs::read_to_string(path).unwrap());
+            }
+        }
+    }
+    assert!(!scripts.is_empty());
+
+    let mut rng = rand::rngs::SmallRng::seed_from_u64(0);
+    for script in &scripts {
+        for _ in 0..mutations {
+            let source = mutate(script, &mut rng);
+            let preprocessed = eisenscript::Preprocessor::new(&source).process();
+            let result = std::panic::catch_unwind(|| {
+                if let Err(err) = preprocessed.parser().rules() {
+                    err.to_string();
+                }
+                // on success this is the same rule set that `rules` returns
+                evaluate(preprocessed.parser().rules_recovering().0);
+            });
+            assert!(
+                result.is_ok(),
+                "parsing or evaluating panicked on:\n{}",
+                source
+            );
+        }
+    }
+}
+
+#[test]
+fn mutated_examples_never_panic() {
+    fuzz(40);
+}
+
+/// A longer run of the above, for `cargo test -- --ignored`.
+#[test]
+#[ignore]
+fn many_mutated_examples_never_panic() {
+    fuzz(200);
+}